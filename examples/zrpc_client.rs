@@ -3,8 +3,10 @@ use crate::pb::user::user_client;
 use std::time::Duration;
 use tonic::Request;
 use tower::ServiceBuilder;
-use zrpc::etcd::discovery::{ClientConf, EtcdDiscovery};
-use zrpc::Client;
+use zrpc::hedge::ClientHedge;
+use zrpc::retry::ClientRetry;
+use zrpc::sre_breaker::ClientSreBreaker;
+use zrpc::{BalancePolicy, Client, ClientConf, P2cLoad};
 
 mod pb;
 
@@ -12,25 +14,63 @@ mod pb;
 struct ClientRpcConf {
     #[serde(rename = "ClientConf")]
     conf: ClientConf,
+    #[serde(rename = "TestServerName")]
+    test_server_name: String,
 }
 
 #[tokio::main]
 async fn main() {
     let conf_data = std::fs::read("examples/cfg/client_conf.yaml").unwrap();
     let client_conf = serde_yaml::from_slice::<ClientRpcConf>(conf_data.as_slice()).unwrap();
-    let etcd_client = client_conf.conf.etcd_conf.new_etcd_client().await.unwrap();
-    let discovery = EtcdDiscovery::new(etcd_client);
-    let client = Client::new(discovery, 50);
+    let discovery = client_conf.conf.new_discovery().await.unwrap();
+    let route_table = client_conf.conf.new_route_table().await.unwrap();
+    // p2c 不能和路由规则、可用区一起用
+    let policy = if route_table.is_none() && client_conf.conf.zone_conf.is_none() {
+        BalancePolicy::P2c(P2cLoad::PeakEwma)
+    } else {
+        BalancePolicy::Weighted
+    };
+    let mut client = Client::new_with_policy(discovery, 50, policy);
+    if let Some(route_table) = route_table {
+        client = client.with_route_table(route_table);
+    }
+    if let Some(zone_conf) = &client_conf.conf.zone_conf {
+        client = client.with_zone(zone_conf.clone());
+    }
+    // 没有配置 Retry 就不重试
+    let retry = client_conf
+        .conf
+        .retry_conf
+        .as_ref()
+        .map(ClientRetry::from)
+        .unwrap_or_default();
+    // 没有配置 Hedge 就不对冲
+    let hedge = client_conf
+        .conf
+        .hedge_conf
+        .as_ref()
+        .map(ClientHedge::from)
+        .unwrap_or_default();
+    let target = format!(
+        "etcd:///{}/{}",
+        client_conf.conf.model, client_conf.test_server_name
+    );
     let mut user_rpc_client = client
-        .new_balance_client(|channel| {
+        .new_balance_client(target, |channel| {
             let channel = ServiceBuilder::new()
                 // Interceptors can be also be applied as middleware
+                // 放在 timeout 外面, 超时也算失败
+                .layer(ClientSreBreaker::new())
+                // 熔断器只记录重试之后的结果, 超时是每次尝试的
+                .layer(hedge.clone())
+                .layer(retry.clone())
                 .timeout(Duration::from_secs(3))
                 // .layer_fn(MyMiddleware::new)
                 .service(channel);
             user_client::UserClient::new(channel)
         })
-        .await;
+        .await
+        .unwrap();
     for _ in 0..100 {
         let request = Request::new(user::AddUserRequest {
             name: "张三".to_string(),
@@ -6,8 +6,11 @@ use std::sync::Mutex;
 use tonic::metadata::MetadataValue;
 use tonic::{Code, Request, Response, Status};
 use tool::log::trace_log::{info, tracing_subscriber};
+use zrpc::deadline::ServerDeadline;
 use zrpc::etcd::register::ServerConf;
+use zrpc::shedder::ServerShedder;
 use zrpc::sre_breaker::ServerSreBreaker;
+use zrpc::timeout::ServerTimeout;
 use zrpc::Server;
 
 mod pb;
@@ -71,19 +74,36 @@ async fn main() {
     let conf_data = std::fs::read("cfg/conf.yaml").unwrap();
     let config: Config = serde_yaml::from_slice(conf_data.as_slice()).unwrap();
     let service_instance = (&config.server_conf).into();
-    let register =
-        zrpc::etcd::register::EtcdRegister::new(&config.server_conf.get_etcd_conf(), 10).await;
+    let register = zrpc::etcd::register::EtcdRegister::new(&config.server_conf.get_etcd_conf(), 10)
+        .await
+        .unwrap();
 
+    // 没有配置 SreBreaker 就用默认参数
+    let sre_breaker = config
+        .server_conf
+        .get_sre_breaker_conf()
+        .map(ServerSreBreaker::from)
+        .unwrap_or_default();
+    let server_timeout = ServerTimeout::from(&config.server_conf);
+    let shedder = config
+        .server_conf
+        .get_shedder_conf()
+        .map(ServerShedder::from)
+        .unwrap_or_default();
     let zrpc_server = Server::new(register, service_instance);
     zrpc_server
-        .serve(|server| {
+        .serve(move |server| {
             server
-                .layer(ServerSreBreaker)
+                .layer(ServerDeadline)
+                .layer(server_timeout.clone())
+                .layer(shedder.clone())
+                .layer(sre_breaker.clone())
                 // .add_service(user_server::UserServer::with_interceptor(
                 //     UserServer::default(),
                 //     check_auth,
                 // ))
                 .add_service(user_server::UserServer::new(UserServer::default()))
         })
-        .await;
+        .await
+        .unwrap();
 }
@@ -0,0 +1,155 @@
+use crate::balance::{Node, Picker, PickerBuilder};
+use crate::common::random;
+use std::sync::Arc;
+use tonic::body::BoxBody;
+use tonic::codegen::http;
+
+// 每个节点在哈希环上的虚拟节点数, 越多分布越均匀
+const VIRTUAL_NODES: usize = 160;
+
+/// 一致性哈希: 按请求里某个 metadata 的值选节点, 同一个值总是落到同一个实例上.
+/// 增减实例的时候只有一小部分值会换节点. 请求里没有这个 metadata 时随机选一个
+#[derive(Debug, Clone)]
+pub struct ConsistentHashPickerBuilder {
+    key: String,
+}
+
+impl ConsistentHashPickerBuilder {
+    /// key: gRPC metadata 的名字, 比如 `x-user-id`
+    pub fn new(key: impl Into<String>) -> Self {
+        Self {
+            key: key.into().to_ascii_lowercase(),
+        }
+    }
+}
+
+impl PickerBuilder for ConsistentHashPickerBuilder {
+    fn build(&self, nodes: Vec<Arc<Node>>) -> Arc<dyn Picker> {
+        // 用地址而不是注册的 key 算位置, key 里带了启动时间, 实例重启后位置就变了
+        let mut ring: Vec<(u64, usize)> = nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(index, node)| {
+                (0..VIRTUAL_NODES).map(move |i| {
+                    let point = format!("{}#{}", node.instance.endpoint, i);
+                    (hash(point.as_bytes()), index)
+                })
+            })
+            .collect();
+        ring.sort_unstable();
+        Arc::new(ConsistentHashPicker {
+            key: self.key.clone(),
+            nodes,
+            ring,
+        })
+    }
+}
+
+struct ConsistentHashPicker {
+    key: String,
+    nodes: Vec<Arc<Node>>,
+    // (哈希值, 节点下标), 按哈希值排好序
+    ring: Vec<(u64, usize)>,
+}
+
+impl Picker for ConsistentHashPicker {
+    fn pick(&self, request: &http::Request<BoxBody>) -> Option<Arc<Node>> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let Some(value) = request.headers().get(&self.key) else {
+            return Some(self.nodes[random(self.nodes.len())].clone());
+        };
+        let hash = hash(value.as_bytes());
+        // 顺时针找到第一个健康的虚拟节点, 超过最后一个就回到开头. 都不健康就用第一个
+        let start = self.ring.partition_point(|(point, _)| *point < hash);
+        let node = (0..self.ring.len())
+            .map(|offset| self.ring[(start + offset) % self.ring.len()].1)
+            .find(|node| self.nodes[*node].is_healthy())
+            .unwrap_or(self.ring[start % self.ring.len()].1);
+        Some(self.nodes[node].clone())
+    }
+}
+
+/// FNV-1a 再做一次 murmur3 的 fmix64 打散. 不用 std 的 DefaultHasher,
+/// 它的算法不保证跨版本一致, 不同版本编译出来的客户端会把同一个值分到不同的实例上
+fn hash(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^= hash >> 33;
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ServiceInstance;
+    use std::collections::HashMap;
+
+    const KEYS: usize = 10000;
+
+    fn nodes(ports: impl IntoIterator<Item = u16>) -> Vec<Arc<Node>> {
+        ports
+            .into_iter()
+            .map(|port| {
+                let endpoint = format!("127.0.0.1:{port}");
+                Node::for_test(ServiceInstance::new("Dev", "user.rpc", endpoint))
+            })
+            .collect()
+    }
+
+    /// 每个 key 落到的节点地址
+    fn assign(nodes: Vec<Arc<Node>>) -> Vec<String> {
+        let picker = ConsistentHashPickerBuilder::new("X-User-Id").build(nodes);
+        (0..KEYS)
+            .map(|key| {
+                let mut request = http::Request::new(tonic::body::empty_body());
+                request
+                    .headers_mut()
+                    .insert("x-user-id", key.to_string().parse().unwrap());
+                picker.pick(&request).unwrap().instance.endpoint.clone()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn balanced() {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for endpoint in assign(nodes(1..=4)) {
+            *counts.entry(endpoint).or_default() += 1;
+        }
+        assert_eq!(counts.len(), 4);
+        assert!(counts.values().all(|count| *count > KEYS / 8), "{counts:?}");
+    }
+
+    #[tokio::test]
+    async fn add_node() {
+        let before = assign(nodes(1..=4));
+        let after = assign(nodes(1..=5));
+        let moved: Vec<_> = before.iter().zip(&after).filter(|(b, a)| b != a).collect();
+        // 只有新节点会分走一部分, 大约 1/5
+        assert!(moved.iter().all(|(_, after)| *after == "127.0.0.1:5"));
+        assert!(
+            moved.len() > KEYS / 10 && moved.len() < KEYS * 3 / 10,
+            "{}",
+            moved.len()
+        );
+    }
+
+    #[tokio::test]
+    async fn remove_node() {
+        let before = assign(nodes(1..=4));
+        let after = assign(nodes([1, 2, 4]));
+        for (before, after) in before.iter().zip(&after) {
+            // 只有被删掉的节点上的 key 换了节点
+            assert_eq!(before == "127.0.0.1:3", before != after);
+        }
+    }
+}
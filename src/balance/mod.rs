@@ -0,0 +1,573 @@
+mod consistent_hash;
+mod p2c;
+mod route;
+mod weighted;
+mod zone;
+
+pub use consistent_hash::ConsistentHashPickerBuilder;
+pub use p2c::P2cLoad;
+pub use route::{RouteConf, RoutePickerBuilder, RouteRule, RouteTable};
+pub use weighted::{WeightedPickerBuilder, DEFAULT_WEIGHT};
+pub use zone::{ZoneConf, ZonePickerBuilder};
+
+use crate::common::ServiceInstance;
+use crate::deadline::propagate_deadline;
+use p2c::{NodeService, P2cService};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, Receiver, UnboundedSender};
+use tokio::sync::{oneshot, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::Sleep;
+use tonic::body::BoxBody;
+use tonic::codegen::{http, BoxFuture};
+use tonic::transport::{Channel, Endpoint};
+use tonic::Status;
+use tool::log::trace_log::{error, info};
+use tower::discover::Change;
+use tower::{BoxError, Service, ServiceExt};
+
+// 连接失败之后多久内认为节点不健康, 期间有请求成功就恢复
+const UNHEALTHY_DURATION: Duration = Duration::from_secs(5);
+// 探测连接的超时和重试的退避
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+// 有节点但是都没有就绪的时候, poll_ready 最多等这么久, 之后的请求直接返回 Unavailable, 不会一直卡住
+const READY_WAIT: Duration = Duration::from_secs(1);
+
+/// 节点的连接和状态, 地址没变的时候复用
+struct NodeState {
+    // 探测任务连上的连接, 请求也走它. 还没连上或者请求连接失败之后是 None
+    channel: RwLock<Option<Channel>>,
+    in_flight: AtomicUsize,
+    // 最近一次连接失败的时间, 请求成功后清空
+    failed_at: Mutex<Option<Instant>>,
+    // 请求连接失败后通知探测任务重新连接
+    unready: Notify,
+    // 等这个节点连上的
+    waiters: Mutex<Vec<Waker>>,
+    // 连上、连接失败、失败后恢复时加一, Picker 用它判断缓存的健康统计要不要重算
+    health_version: AtomicU64,
+}
+
+impl NodeState {
+    fn new() -> Self {
+        Self {
+            channel: RwLock::new(None),
+            in_flight: AtomicUsize::new(0),
+            failed_at: Mutex::new(None),
+            unready: Notify::new(),
+            waiters: Mutex::new(Vec::new()),
+            health_version: AtomicU64::new(0),
+        }
+    }
+
+    fn connected(&self, channel: Channel) {
+        *self.channel.write().unwrap() = Some(channel);
+        self.health_version.fetch_add(1, Ordering::Release);
+        for waker in self.waiters.lock().unwrap().drain(..) {
+            waker.wake();
+        }
+    }
+
+    fn register(&self, waker: &Waker) {
+        let mut waiters = self.waiters.lock().unwrap();
+        if !waiters.iter().any(|w| w.will_wake(waker)) {
+            waiters.push(waker.clone());
+        }
+    }
+
+    fn mark(&self, success: bool) {
+        let recovered = {
+            let mut failed_at = self.failed_at.lock().unwrap();
+            std::mem::replace(&mut *failed_at, (!success).then(Instant::now)).is_some()
+        };
+        // 连接断了, 重新连上之前不再选它
+        if !success && self.channel.write().unwrap().take().is_some() {
+            self.unready.notify_one();
+        }
+        if !success || recovered {
+            self.health_version.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    fn get_health_version(&self) -> u64 {
+        self.health_version.load(Ordering::Acquire)
+    }
+
+    fn is_ready(&self) -> bool {
+        self.channel.read().unwrap().is_some()
+    }
+
+    /// 最近连接失败过的话, 返回恢复健康的时间
+    fn get_recover_at(&self) -> Option<Instant> {
+        let failed_at = (*self.failed_at.lock().unwrap())?;
+        Some(failed_at + UNHEALTHY_DURATION).filter(|recover_at| *recover_at > Instant::now())
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.is_ready() && self.get_recover_at().is_none()
+    }
+
+    async fn send(
+        self: Arc<Self>,
+        request: http::Request<BoxBody>,
+    ) -> Result<http::Response<BoxBody>, BoxError> {
+        let channel = self.channel.read().unwrap().clone();
+        let channel = channel.ok_or_else(|| Status::unavailable("endpoint not connected"))?;
+        let _in_flight = InFlight::new(&self.in_flight);
+        let response = channel.oneshot(request).await;
+        self.mark(response.is_ok());
+        Ok(response?)
+    }
+}
+
+/// 连接节点, 连上之后这个连接交给请求用. 请求连接失败后重新连接, 连不上按指数退避重试.
+/// 节点被移除时 drop 掉, 探测任务跟着退出
+struct Probe(JoinHandle<()>);
+
+impl Probe {
+    fn spawn(endpoint: Endpoint, state: Arc<NodeState>) -> Self {
+        let endpoint = endpoint.connect_timeout(PROBE_TIMEOUT);
+        Self(tokio::spawn(async move {
+            let mut backoff = MIN_BACKOFF;
+            loop {
+                match endpoint.connect().await {
+                    Ok(channel) => {
+                        backoff = MIN_BACKOFF;
+                        state.connected(channel);
+                        state.unready.notified().await;
+                        continue;
+                    }
+                    Err(err) => info!(
+                        "probe {} failed: {}, retry after {:?}",
+                        endpoint.uri(),
+                        err,
+                        backoff
+                    ),
+                }
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }))
+    }
+}
+
+impl Drop for Probe {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// 请求结束(拿到响应或者失败)时减少进行中的请求数
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl<'a> InFlight<'a> {
+    fn new(in_flight: &'a AtomicUsize) -> Self {
+        in_flight.fetch_add(1, Ordering::Relaxed);
+        Self(in_flight)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 重试、对冲时最多重新选几次, 尽量不选已经发过的节点
+const MAX_PICKS: usize = 3;
+
+/// 同一个调用的多次尝试共享, 放在请求的 extensions 里. 记录已经发过的节点, 下一次尽量换一个
+#[derive(Clone, Default)]
+pub(crate) struct TriedEndpoints(Arc<Mutex<Vec<String>>>);
+
+impl TriedEndpoints {
+    /// 选一个没有发过的节点, 选不到就用最后一次选到的
+    fn pick(&self, picker: &dyn Picker, request: &http::Request<BoxBody>) -> Option<Arc<Node>> {
+        let mut tried = self.0.lock().unwrap();
+        let mut node = picker.pick(request)?;
+        for _ in 1..MAX_PICKS {
+            if !tried.contains(&node.key) {
+                break;
+            }
+            node = picker.pick(request)?;
+        }
+        tried.push(node.key.clone());
+        Some(node)
+    }
+}
+
+/// 负载均衡中的一个节点. 实例信息变了但是地址没变的时候, 复用原来的连接
+pub struct Node {
+    key: String,
+    instance: ServiceInstance,
+    state: Arc<NodeState>,
+    probe: Arc<Probe>,
+}
+
+impl Node {
+    pub fn get_key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn get_instance(&self) -> &ServiceInstance {
+        &self.instance
+    }
+
+    /// 正在进行中的请求数
+    pub fn get_in_flight(&self) -> usize {
+        self.state.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// 已经连上, 并且之后的请求没有连接失败过
+    pub fn is_ready(&self) -> bool {
+        self.state.is_ready()
+    }
+
+    /// 已经就绪, 并且最近没有连接失败过. 只看连接层面的错误, 服务端返回的 grpc 错误不算
+    pub fn is_healthy(&self) -> bool {
+        self.state.is_healthy()
+    }
+}
+
+/// 每个请求由 Picker 选出一个节点, 没有可用节点时返回 None
+pub trait Picker: Send + Sync {
+    fn pick(&self, request: &http::Request<BoxBody>) -> Option<Arc<Node>>;
+}
+
+/// 节点有变化的时候用全部节点重新构建 Picker
+pub trait PickerBuilder: Send + Sync {
+    fn build(&self, nodes: Vec<Arc<Node>>) -> Arc<dyn Picker>;
+}
+
+/// 客户端的负载均衡策略
+#[derive(Clone, Default)]
+pub enum BalancePolicy {
+    /// 按注册的权重平滑加权轮询, 都没有配置权重时就是轮询
+    #[default]
+    Weighted,
+    /// 用 tower 的 p2c: 随机选两个节点, 把请求给负载低的那个.
+    /// 它自己持有全部节点, 不能和路由规则、可用区一起用
+    P2c(P2cLoad),
+    /// 按请求里这个 metadata 的值做一致性哈希, 比如 `x-user-id`
+    ConsistentHash(String),
+    /// 自定义选节点的逻辑
+    Custom(Arc<dyn PickerBuilder>),
+}
+
+impl BalancePolicy {
+    pub(crate) fn picker_builder(&self) -> Arc<dyn PickerBuilder> {
+        match self {
+            BalancePolicy::Weighted => Arc::new(WeightedPickerBuilder),
+            BalancePolicy::P2c(_) => unreachable!("P2c is balanced by tower"),
+            BalancePolicy::ConsistentHash(key) => Arc::new(ConsistentHashPickerBuilder::new(key)),
+            BalancePolicy::Custom(builder) => builder.clone(),
+        }
+    }
+}
+
+struct EmptyPicker;
+
+impl Picker for EmptyPicker {
+    fn pick(&self, _request: &http::Request<BoxBody>) -> Option<Arc<Node>> {
+        None
+    }
+}
+
+struct Shared {
+    picker: RwLock<Arc<dyn Picker>>,
+    nodes: RwLock<Vec<Arc<Node>>>,
+    // 在 poll_ready 里等节点列表变化的
+    waiters: Mutex<Vec<Waker>>,
+    // 最后一个 BalanceChannel 被丢弃时通知 Balancer 退出
+    _closed: oneshot::Sender<()>,
+}
+
+impl Shared {
+    /// 一个节点都没有的时候也算, 让 call 去返回 Unavailable
+    fn has_ready_node(&self) -> bool {
+        let nodes = self.nodes.read().unwrap();
+        nodes.is_empty() || nodes.iter().any(|node| node.is_ready())
+    }
+
+    /// 节点列表变化或者任意一个节点连上时唤醒
+    fn register(&self, waker: &Waker) {
+        {
+            let mut waiters = self.waiters.lock().unwrap();
+            if !waiters.iter().any(|w| w.will_wake(waker)) {
+                waiters.push(waker.clone());
+            }
+        }
+        for node in self.nodes.read().unwrap().iter() {
+            node.state.register(waker);
+        }
+    }
+
+    fn wake(&self) {
+        for waker in self.waiters.lock().unwrap().drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+enum Inner {
+    Picker {
+        shared: Arc<Shared>,
+        // 在 poll_ready 里等节点就绪的超时
+        ready_wait: Option<Pin<Box<Sleep>>>,
+    },
+    P2c(P2cService),
+}
+
+/// zrpc 自己的负载均衡, 替代 tonic 的 `Channel::balance_channel`, 选节点的时候可以看到实例的注册信息
+pub struct BalanceChannel(Inner);
+
+impl Clone for BalanceChannel {
+    fn clone(&self) -> Self {
+        Self(match &self.0 {
+            Inner::Picker { shared, .. } => Inner::Picker {
+                shared: shared.clone(),
+                ready_wait: None,
+            },
+            Inner::P2c(service) => Inner::P2c(service.clone()),
+        })
+    }
+}
+
+impl BalanceChannel {
+    pub(crate) fn new(builder: Arc<dyn PickerBuilder>) -> (Self, Balancer) {
+        let (closed_tx, closed_rx) = oneshot::channel();
+        let shared = Arc::new(Shared {
+            picker: RwLock::new(Arc::new(EmptyPicker)),
+            nodes: RwLock::new(Vec::new()),
+            waiters: Mutex::new(Vec::new()),
+            _closed: closed_tx,
+        });
+        let balancer = Balancer {
+            target: Target::Picker {
+                shared: Arc::downgrade(&shared),
+                closed: closed_rx,
+                builder,
+            },
+            nodes: HashMap::new(),
+        };
+        let channel = Self(Inner::Picker {
+            shared,
+            ready_wait: None,
+        });
+        (channel, balancer)
+    }
+
+    /// 节点交给 tower 的 p2c, Balancer 只负责连接节点. 要在 tokio 的运行时里调用
+    pub(crate) fn p2c(load: P2cLoad) -> (Self, Balancer) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let balancer = Balancer {
+            target: Target::P2c(sender),
+            nodes: HashMap::new(),
+        };
+        (Self(Inner::P2c(p2c::new_service(load, receiver))), balancer)
+    }
+}
+
+impl Service<http::Request<BoxBody>> for BalanceChannel {
+    type Response = http::Response<BoxBody>;
+    type Error = BoxError;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let (shared, ready_wait) = match &mut self.0 {
+            Inner::Picker { shared, ready_wait } => (shared, ready_wait),
+            Inner::P2c(service) => return service.poll_ready(cx),
+        };
+        // 有节点就绪就可以发, 选哪个节点在 call 里决定. 每个节点的 Channel 自己有缓冲, 在 call 里面等它
+        if shared.has_ready_node() {
+            *ready_wait = None;
+            return Poll::Ready(Ok(()));
+        }
+        shared.register(cx.waker());
+        // 注册之后再看一次, 避免刚好错过唤醒
+        let sleep = ready_wait.get_or_insert_with(|| Box::pin(tokio::time::sleep(READY_WAIT)));
+        if shared.has_ready_node() || sleep.as_mut().poll(cx).is_ready() {
+            *ready_wait = None;
+            return Poll::Ready(Ok(()));
+        }
+        Poll::Pending
+    }
+
+    fn call(&mut self, mut request: http::Request<BoxBody>) -> Self::Future {
+        // 在服务端的 handler 里调用时带上剩下的时间, 重试的每次尝试都重新算
+        if let Err(err) = propagate_deadline(&mut request) {
+            return Box::pin(async move { Err(err) });
+        }
+        let shared = match &mut self.0 {
+            Inner::Picker { shared, .. } => shared,
+            Inner::P2c(service) => return Box::pin(service.call(request)),
+        };
+        let picker = shared.picker.read().unwrap().clone();
+        let node = match request.extensions().get::<TriedEndpoints>() {
+            Some(tried) => tried.pick(picker.as_ref(), &request),
+            None => picker.pick(&request),
+        };
+        Box::pin(async move {
+            let node = node.ok_or_else(|| Status::unavailable("no available endpoint"))?;
+            node.state.clone().send(request).await
+        })
+    }
+}
+
+/// 节点变化之后交给谁
+enum Target {
+    // 重建 Picker
+    Picker {
+        shared: Weak<Shared>,
+        closed: oneshot::Receiver<()>,
+        builder: Arc<dyn PickerBuilder>,
+    },
+    // 连接有变化的节点发给 tower 的 p2c
+    P2c(UnboundedSender<Change<String, NodeService>>),
+}
+
+impl Target {
+    /// BalanceChannel 全部被丢弃
+    async fn closed(&mut self) {
+        match self {
+            Target::Picker { closed, .. } => {
+                let _ = closed.await;
+            }
+            Target::P2c(sender) => sender.closed().await,
+        }
+    }
+}
+
+/// 接收服务发现推过来的实例变化, 维护节点列表和连接
+pub(crate) struct Balancer {
+    target: Target,
+    nodes: HashMap<String, Arc<Node>>,
+}
+
+impl Balancer {
+    /// 返回节点是否有变化
+    fn apply(&mut self, change: Change<String, ServiceInstance>) -> bool {
+        match change {
+            Change::Insert(key, instance) => {
+                let (state, probe) = match self.nodes.get(&key) {
+                    Some(node) if node.instance == instance => return false,
+                    // 只是权重之类的信息变了, 不需要重建连接
+                    Some(node) if node.instance.endpoint == instance.endpoint => {
+                        (node.state.clone(), node.probe.clone())
+                    }
+                    _ => match instance.to_endpoint() {
+                        Ok(endpoint) => {
+                            let state = Arc::new(NodeState::new());
+                            let probe = Probe::spawn(endpoint, state.clone());
+                            if let Target::P2c(sender) = &self.target {
+                                let _ = sender
+                                    .send(Change::Insert(key.clone(), NodeService(state.clone())));
+                            }
+                            (state, Arc::new(probe))
+                        }
+                        Err(_) => {
+                            error!("invalid endpoint: {}", instance.endpoint);
+                            return false;
+                        }
+                    },
+                };
+                self.nodes.insert(
+                    key.clone(),
+                    Arc::new(Node {
+                        key,
+                        instance,
+                        state,
+                        probe,
+                    }),
+                );
+                true
+            }
+            Change::Remove(key) => {
+                if self.nodes.remove(&key).is_none() {
+                    return false;
+                }
+                if let Target::P2c(sender) = &self.target {
+                    let _ = sender.send(Change::Remove(key));
+                }
+                true
+            }
+        }
+    }
+
+    /// 用当前的节点重建 Picker, BalanceChannel 已经没了返回 false
+    fn publish(&self) -> bool {
+        let (shared, builder) = match &self.target {
+            Target::Picker {
+                shared, builder, ..
+            } => (shared, builder),
+            Target::P2c(sender) => return !sender.is_closed(),
+        };
+        let Some(shared) = shared.upgrade() else {
+            return false;
+        };
+        let mut nodes: Vec<Arc<Node>> = self.nodes.values().cloned().collect();
+        nodes.sort_by(|a, b| a.key.cmp(&b.key));
+        let picker = builder.build(nodes.clone());
+        *shared.picker.write().unwrap() = picker;
+        *shared.nodes.write().unwrap() = nodes;
+        shared.wake();
+        true
+    }
+
+    /// 处理完 receiver 里的全部变化(发送端都关闭)之后再构建 Picker, 用于第一次全量加载
+    pub(crate) async fn sync(&mut self, mut receiver: Receiver<Change<String, ServiceInstance>>) {
+        while let Some(change) = receiver.recv().await {
+            self.apply(change);
+        }
+        self.publish();
+    }
+
+    /// 持续处理服务发现推过来的变化, BalanceChannel 全部被丢弃后退出, 服务发现那边随之退出
+    pub(crate) async fn run(mut self, mut receiver: Receiver<Change<String, ServiceInstance>>) {
+        loop {
+            let change = tokio::select! {
+                _ = self.target.closed() => return,
+                change = receiver.recv() => change,
+            };
+            let Some(change) = change else {
+                return;
+            };
+            let mut changed = self.apply(change);
+            // 积压的变化一次处理完再重建 Picker
+            while let Ok(change) = receiver.try_recv() {
+                changed |= self.apply(change);
+            }
+            if changed && !self.publish() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl Node {
+    /// 测试用的节点, 不探测, 直接用一个懒连接当作已经连上
+    pub(crate) fn for_test(instance: ServiceInstance) -> Arc<Node> {
+        let state = Arc::new(NodeState::new());
+        state.connected(instance.to_endpoint().unwrap().connect_lazy());
+        Arc::new(Node {
+            key: instance.key.clone(),
+            instance,
+            state,
+            probe: Arc::new(Probe(tokio::spawn(async {}))),
+        })
+    }
+
+    /// 当作请求连接失败
+    pub(crate) fn fail_for_test(&self) {
+        self.state.mark(false);
+    }
+}
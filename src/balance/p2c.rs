@@ -0,0 +1,273 @@
+use crate::balance::{NodeState, READY_WAIT};
+use futures_core::Stream;
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::Sleep;
+use tonic::body::BoxBody;
+use tonic::codegen::{http, BoxFuture};
+use tonic::Status;
+use tower::balance::p2c::Balance;
+use tower::buffer::Buffer;
+use tower::discover::Change;
+use tower::load::{CompleteOnResponse, PeakEwmaDiscover, PendingRequestsDiscover};
+use tower::util::BoxService;
+use tower::{BoxError, Service};
+
+// 还没有请求完成过的节点, 按这个耗时估算
+const DEFAULT_RTT: Duration = Duration::from_millis(30);
+// 耗时的衰减周期, 越大对历史耗时记得越久
+const DECAY: Duration = Duration::from_secs(10);
+// 等待 p2c 处理的请求数, 和 tonic 的 Channel 默认的一样
+const BUFFER_SIZE: usize = 1024;
+
+type Request = http::Request<BoxBody>;
+type Response = http::Response<BoxBody>;
+
+pub(super) type P2cService = Buffer<ReadyWait<BoxService<Request, Response, BoxError>>, Request>;
+
+/// P2c 比较两个节点负载的方式
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum P2cLoad {
+    /// 峰值 EWMA 耗时乘以进行中的请求数, 慢的和忙的节点都会少分流量
+    #[default]
+    PeakEwma,
+    /// 只看进行中的请求数
+    PendingRequests,
+}
+
+/// p2c 里的一个节点. 没有连上的时候 poll_ready 返回 Pending, 不会被选中, 连上之后唤醒
+pub(super) struct NodeService(pub(super) Arc<NodeState>);
+
+impl Service<Request> for NodeService {
+    type Response = Response;
+    type Error = BoxError;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.0.is_ready() {
+            return Poll::Ready(Ok(()));
+        }
+        self.0.register(cx.waker());
+        // 注册之后再看一次, 避免刚好错过唤醒
+        if self.0.is_ready() {
+            return Poll::Ready(Ok(()));
+        }
+        Poll::Pending
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        Box::pin(self.0.clone().send(request))
+    }
+}
+
+/// Balancer 推过来的节点变化
+struct NodeDiscover<S>(UnboundedReceiver<Change<String, S>>);
+
+impl<S> Stream for NodeDiscover<S> {
+    type Item = Result<Change<String, S>, Infallible>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_recv(cx).map(|change| change.map(Ok))
+    }
+}
+
+/// 节点都没有就绪的时候, poll_ready 最多等 READY_WAIT, 之后的请求直接返回 Unavailable,
+/// 不会一直卡在 Buffer 里
+pub(super) struct ReadyWait<S> {
+    inner: S,
+    wait: Option<Pin<Box<Sleep>>>,
+    expired: bool,
+}
+
+impl<S> Service<Request> for ReadyWait<S>
+where
+    S: Service<Request, Response = Response, Error = BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = BoxError;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.inner.poll_ready(cx)?.is_ready() {
+            self.wait = None;
+            return Poll::Ready(Ok(()));
+        }
+        let wait = self
+            .wait
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(READY_WAIT)));
+        if wait.as_mut().poll(cx).is_ready() {
+            self.wait = None;
+            self.expired = true;
+            return Poll::Ready(Ok(()));
+        }
+        Poll::Pending
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        if std::mem::take(&mut self.expired) {
+            return Box::pin(async { Err(Status::unavailable("no available endpoint").into()) });
+        }
+        Box::pin(self.inner.call(request))
+    }
+}
+
+fn balance<S>(
+    load: P2cLoad,
+    receiver: UnboundedReceiver<Change<String, S>>,
+) -> BoxService<Request, Response, BoxError>
+where
+    S: Service<Request, Response = Response, Error = BoxError> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let discover = NodeDiscover(receiver);
+    let completion = CompleteOnResponse::default();
+    match load {
+        P2cLoad::PeakEwma => BoxService::new(Balance::new(PeakEwmaDiscover::new(
+            discover,
+            DEFAULT_RTT,
+            DECAY,
+            completion,
+        ))),
+        P2cLoad::PendingRequests => BoxService::new(Balance::new(PendingRequestsDiscover::new(
+            discover, completion,
+        ))),
+    }
+}
+
+/// tower 的 p2c 放在 Buffer 后面, 每个 BalanceChannel 克隆一份 Buffer 的句柄.
+/// 要在 tokio 的运行时里调用
+pub(super) fn new_service(
+    load: P2cLoad,
+    receiver: UnboundedReceiver<Change<String, NodeService>>,
+) -> P2cService {
+    let service = ReadyWait {
+        inner: balance(load, receiver),
+        wait: None,
+        expired: false,
+    };
+    Buffer::new(service, BUFFER_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Instant;
+    use tokio::sync::mpsc;
+    use tonic::body::empty_body;
+    use tonic::transport::Endpoint;
+    use tonic::Code;
+    use tower::ServiceExt;
+
+    fn request() -> Request {
+        http::Request::new(empty_body())
+    }
+
+    /// 记录每个请求发给了哪个节点, 响应的 future 不 poll 就一直算进行中
+    fn mock(
+        name: &'static str,
+        calls: Arc<Mutex<Vec<&'static str>>>,
+    ) -> BoxService<Request, Response, BoxError> {
+        BoxService::new(tower::service_fn(move |_request: Request| {
+            calls.lock().unwrap().push(name);
+            std::future::ready(Ok::<_, BoxError>(http::Response::new(empty_body())))
+        }))
+    }
+
+    #[tokio::test]
+    async fn prefer_idle() {
+        for load in [P2cLoad::PeakEwma, P2cLoad::PendingRequests] {
+            let calls = Arc::new(Mutex::new(Vec::new()));
+            let (sender, receiver) = mpsc::unbounded_channel();
+            for name in ["a", "b"] {
+                let change = Change::Insert(name.to_owned(), mock(name, calls.clone()));
+                sender.send(change).unwrap();
+            }
+            let mut service = balance(load, receiver);
+            // 第一个请求一直不结束, 那个节点上有一个进行中的请求
+            let _loaded = service.ready().await.unwrap().call(request());
+            for _ in 0..20 {
+                service
+                    .ready()
+                    .await
+                    .unwrap()
+                    .call(request())
+                    .await
+                    .unwrap();
+            }
+            let calls = calls.lock().unwrap();
+            assert_eq!(calls.len(), 21);
+            assert!(calls[1..].iter().all(|name| *name != calls[0]), "{load:?}");
+        }
+    }
+
+    fn lazy_channel() -> tonic::transport::Channel {
+        // 没有监听的端口, 请求会连接失败
+        Endpoint::from_static("http://127.0.0.1:1").connect_lazy()
+    }
+
+    #[tokio::test]
+    async fn skip_unready() {
+        let a = Arc::new(NodeState::new());
+        a.connected(lazy_channel());
+        let b = Arc::new(NodeState::new());
+        let (sender, receiver) = mpsc::unbounded_channel();
+        sender
+            .send(Change::Insert("a".to_owned(), NodeService(a.clone())))
+            .unwrap();
+        sender
+            .send(Change::Insert("b".to_owned(), NodeService(b.clone())))
+            .unwrap();
+        let mut service = new_service(P2cLoad::PeakEwma, receiver);
+
+        // 只会选连上的 a, 请求连接失败之后 a 也不再就绪
+        assert!(service
+            .ready()
+            .await
+            .unwrap()
+            .call(request())
+            .await
+            .is_err());
+        assert!(a.failed_at.lock().unwrap().is_some());
+        assert!(!a.is_ready());
+        assert!(b.failed_at.lock().unwrap().is_none());
+
+        // 都没有就绪, 等 READY_WAIT 之后返回 Unavailable
+        let start = Instant::now();
+        let err = service
+            .ready()
+            .await
+            .unwrap()
+            .call(request())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Status>().unwrap().code(),
+            Code::Unavailable
+        );
+        assert!(start.elapsed() >= READY_WAIT);
+
+        // b 连上之后被唤醒, 不用等到超时
+        let start = Instant::now();
+        let connect = b.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            connect.connected(lazy_channel());
+        });
+        assert!(service
+            .ready()
+            .await
+            .unwrap()
+            .call(request())
+            .await
+            .is_err());
+        assert!(b.failed_at.lock().unwrap().is_some());
+        assert!(start.elapsed() < READY_WAIT);
+    }
+}
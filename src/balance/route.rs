@@ -0,0 +1,312 @@
+use crate::balance::{Node, Picker, PickerBuilder};
+use crate::common::random;
+use crate::common::ServiceInstance;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tokio::sync::oneshot;
+use tonic::body::BoxBody;
+use tonic::codegen::http;
+
+/// 一条路由规则: 请求头都匹配并且落在 Percent 比例内的请求, 发给 Version 和 Metadata 都匹配的实例.
+/// 比如 `Percent: 5, Version: v2` 是 5% 的流量给 v2,
+/// `Headers: {x-canary: "true"}, Metadata: {tag: canary}` 是带了 x-canary 的请求只发给金丝雀实例
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RouteRule {
+    #[serde(rename = "Name")]
+    pub name: String,
+    // 请求的 metadata 要全部相等, 为空时匹配所有请求
+    #[serde(rename = "Headers", skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+    // 匹配的请求中路由到这组实例的百分比
+    #[serde(rename = "Percent")]
+    pub percent: f64,
+    #[serde(rename = "Version", skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    // 实例注册的 metadata 要包含这些, 比如 tag
+    #[serde(rename = "Metadata", skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+}
+
+impl Default for RouteRule {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            headers: HashMap::new(),
+            percent: 100.0,
+            version: None,
+            metadata: HashMap::new(),
+        }
+    }
+}
+
+impl RouteRule {
+    fn match_instance(&self, instance: &ServiceInstance) -> bool {
+        self.version
+            .as_ref()
+            .is_none_or(|version| instance.version.as_ref() == Some(version))
+            && self
+                .metadata
+                .iter()
+                .all(|(key, value)| instance.metadata.get(key) == Some(value))
+    }
+
+    fn match_request(&self, request: &http::Request<BoxBody>) -> bool {
+        let headers_match = self.headers.iter().all(|(key, value)| {
+            request
+                .headers()
+                .get(key.as_str())
+                .is_some_and(|header| header.as_bytes() == value.as_bytes())
+        });
+        headers_match && (random(10000) as f64) < self.percent * 100.0
+    }
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct RouteConf {
+    #[serde(rename = "Rules", default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<RouteRule>,
+    // 配置了就从 etcd 的这个 key 加载规则(yaml 或者 json 的列表)并 watch 热更新,
+    // key 不存在的时候用上面的 Rules
+    #[serde(rename = "EtcdKey", skip_serializing_if = "Option::is_none")]
+    pub etcd_key: Option<String>,
+}
+
+/// 当前生效的路由规则, 克隆出来的共享同一份, 更新后下一个请求就会按新规则路由
+#[derive(Clone, Default)]
+pub struct RouteTable {
+    rules: Arc<RwLock<Arc<Vec<RouteRule>>>>,
+    // 从 etcd watch 规则的时候有, 最后一个 RouteTable 被丢弃时通知 watch 退出
+    _closed: Option<Arc<oneshot::Sender<()>>>,
+}
+
+impl RouteTable {
+    pub fn new(rules: Vec<RouteRule>) -> Self {
+        Self {
+            rules: Arc::new(RwLock::new(Arc::new(rules))),
+            _closed: None,
+        }
+    }
+
+    pub(crate) fn with_closed(self, closed: oneshot::Sender<()>) -> Self {
+        Self {
+            _closed: Some(Arc::new(closed)),
+            ..self
+        }
+    }
+
+    pub fn update(&self, rules: Vec<RouteRule>) {
+        *self.rules.write().unwrap() = Arc::new(rules);
+    }
+
+    pub fn get_rules(&self) -> Arc<Vec<RouteRule>> {
+        self.rules.read().unwrap().clone()
+    }
+}
+
+/// 按路由规则把节点分组, 每组再用 inner 选节点. 没有命中规则的请求发给不属于任何规则的稳定节点
+pub struct RoutePickerBuilder {
+    route_table: RouteTable,
+    inner: Arc<dyn PickerBuilder>,
+}
+
+impl RoutePickerBuilder {
+    pub fn new(route_table: RouteTable, inner: Arc<dyn PickerBuilder>) -> Self {
+        Self { route_table, inner }
+    }
+}
+
+impl PickerBuilder for RoutePickerBuilder {
+    fn build(&self, nodes: Vec<Arc<Node>>) -> Arc<dyn Picker> {
+        let routed = Routed::new(self.route_table.get_rules(), &nodes, &self.inner);
+        Arc::new(RoutePicker {
+            route_table: self.route_table.clone(),
+            inner: self.inner.clone(),
+            nodes,
+            routed: RwLock::new(Arc::new(routed)),
+        })
+    }
+}
+
+/// 按某个版本的规则分好组的节点
+struct Routed {
+    rules: Arc<Vec<RouteRule>>,
+    pickers: Vec<Arc<dyn Picker>>,
+    stable: Arc<dyn Picker>,
+}
+
+impl Routed {
+    fn new(
+        rules: Arc<Vec<RouteRule>>,
+        nodes: &[Arc<Node>],
+        inner: &Arc<dyn PickerBuilder>,
+    ) -> Self {
+        let group = |f: &dyn Fn(&ServiceInstance) -> bool| {
+            let nodes = nodes
+                .iter()
+                .filter(|node| f(&node.instance))
+                .cloned()
+                .collect();
+            inner.build(nodes)
+        };
+        let pickers = rules
+            .iter()
+            .map(|rule| group(&|instance| rule.match_instance(instance)))
+            .collect();
+        let has_stable = nodes
+            .iter()
+            .any(|node| !rules.iter().any(|rule| rule.match_instance(&node.instance)));
+        // 所有实例都被规则选中了就没有稳定节点, 这时候用全部节点兜底
+        let stable = if has_stable {
+            group(&|instance| !rules.iter().any(|rule| rule.match_instance(instance)))
+        } else {
+            group(&|_| true)
+        };
+        Self {
+            rules,
+            pickers,
+            stable,
+        }
+    }
+}
+
+struct RoutePicker {
+    route_table: RouteTable,
+    inner: Arc<dyn PickerBuilder>,
+    nodes: Vec<Arc<Node>>,
+    routed: RwLock<Arc<Routed>>,
+}
+
+impl RoutePicker {
+    /// 规则热更新之后, 第一个请求进来时按新规则重新分组
+    fn routed(&self) -> Arc<Routed> {
+        let rules = self.route_table.get_rules();
+        let routed = self.routed.read().unwrap().clone();
+        if Arc::ptr_eq(&routed.rules, &rules) {
+            return routed;
+        }
+        let routed = Arc::new(Routed::new(rules, &self.nodes, &self.inner));
+        *self.routed.write().unwrap() = routed.clone();
+        routed
+    }
+}
+
+impl Picker for RoutePicker {
+    fn pick(&self, request: &http::Request<BoxBody>) -> Option<Arc<Node>> {
+        let routed = self.routed();
+        for (rule, picker) in routed.rules.iter().zip(&routed.pickers) {
+            if !rule.match_request(request) {
+                continue;
+            }
+            // 规则对应的实例都没了, 回退到稳定节点
+            if let Some(node) = picker.pick(request) {
+                return Some(node);
+            }
+        }
+        routed.stable.pick(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balance::WeightedPickerBuilder;
+
+    // 1 是稳定版本, 2 是金丝雀
+    fn nodes() -> Vec<Arc<Node>> {
+        let mut stable = ServiceInstance::new("Dev", "user.rpc", "127.0.0.1:1".to_owned());
+        stable.version = Some("v1".to_owned());
+        let mut canary = ServiceInstance::new("Dev", "user.rpc", "127.0.0.1:2".to_owned());
+        canary.version = Some("v2".to_owned());
+        canary
+            .metadata
+            .insert("tag".to_owned(), "canary".to_owned());
+        vec![Node::for_test(stable), Node::for_test(canary)]
+    }
+
+    fn rules(yaml: &str) -> Vec<RouteRule> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn picker(route_table: &RouteTable) -> Arc<dyn Picker> {
+        RoutePickerBuilder::new(route_table.clone(), Arc::new(WeightedPickerBuilder)).build(nodes())
+    }
+
+    fn pick(picker: &dyn Picker, header: Option<(&'static str, &'static str)>) -> String {
+        let mut request = http::Request::new(tonic::body::empty_body());
+        if let Some((key, value)) = header {
+            request
+                .headers_mut()
+                .insert(key, http::HeaderValue::from_static(value));
+        }
+        picker.pick(&request).unwrap().instance.endpoint.clone()
+    }
+
+    #[tokio::test]
+    async fn header_match() {
+        let table = RouteTable::new(rules(
+            "- Headers: {x-canary: 'true'}\n  Metadata: {tag: canary}\n",
+        ));
+        let picker = picker(&table);
+        for _ in 0..10 {
+            assert_eq!(
+                pick(picker.as_ref(), Some(("x-canary", "true"))),
+                "127.0.0.1:2"
+            );
+            assert_eq!(
+                pick(picker.as_ref(), Some(("x-canary", "false"))),
+                "127.0.0.1:1"
+            );
+            assert_eq!(pick(picker.as_ref(), None), "127.0.0.1:1");
+        }
+    }
+
+    #[tokio::test]
+    async fn percent() {
+        let table = RouteTable::new(rules("- Percent: 30\n  Version: v2\n"));
+        let picker = picker(&table);
+        let canary = (0..1000)
+            .filter(|_| pick(picker.as_ref(), None) == "127.0.0.1:2")
+            .count();
+        assert!((200..400).contains(&canary), "{canary}");
+    }
+
+    #[tokio::test]
+    async fn fallback_to_stable() {
+        // 第一条规则对应的实例不存在, 金丝雀实例被第二条规则占用
+        let table = RouteTable::new(rules(
+            "- Headers: {x-canary: 'true'}\n  Version: v3\n- Headers: {x-beta: 'true'}\n  Version: v2\n",
+        ));
+        let picker = picker(&table);
+        for _ in 0..10 {
+            assert_eq!(
+                pick(picker.as_ref(), Some(("x-canary", "true"))),
+                "127.0.0.1:1"
+            );
+        }
+        // 所有实例都被规则选中了, 没命中的请求用全部实例
+        let table = RouteTable::new(rules("- Headers: {x-canary: 'true'}\n"));
+        let picker = self::picker(&table);
+        let mut picked: Vec<String> = (0..4).map(|_| pick(picker.as_ref(), None)).collect();
+        picked.sort();
+        picked.dedup();
+        assert_eq!(picked, ["127.0.0.1:1", "127.0.0.1:2"]);
+    }
+
+    #[tokio::test]
+    async fn regroup_on_update() {
+        let table = RouteTable::new(rules("- Headers: {x-canary: 'true'}\n  Version: v2\n"));
+        let picker = picker(&table);
+        assert_eq!(pick(picker.as_ref(), None), "127.0.0.1:1");
+        // 不用重建 Picker, 下一个请求就按新规则
+        table.update(rules("- Version: v2\n"));
+        for _ in 0..10 {
+            assert_eq!(pick(picker.as_ref(), None), "127.0.0.1:2");
+        }
+        table.update(Vec::new());
+        let mut picked: Vec<String> = (0..4).map(|_| pick(picker.as_ref(), None)).collect();
+        picked.sort();
+        picked.dedup();
+        assert_eq!(picked, ["127.0.0.1:1", "127.0.0.1:2"]);
+    }
+}
@@ -0,0 +1,126 @@
+use crate::balance::{Node, Picker, PickerBuilder};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use tonic::body::BoxBody;
+use tonic::codegen::http;
+
+/// 注册信息里没有配置权重的实例使用的权重
+pub const DEFAULT_WEIGHT: u32 = 100;
+
+/// 按实例的权重分配流量, 平滑加权轮询(和 nginx 的一样), 权重为 0 的和不健康的实例不分配流量.
+/// 都没有配置权重的时候就是普通的轮询. 每个节点的 current 单独原子更新, 不用加锁,
+/// 并发选的时候顺序不是严格的, 但是总量不变, 分配的比例还是按权重
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WeightedPickerBuilder;
+
+struct WeightedNode {
+    node: Arc<Node>,
+    weight: i64,
+    current: AtomicI64,
+}
+
+struct WeightedPicker {
+    nodes: Vec<WeightedNode>,
+}
+
+impl PickerBuilder for WeightedPickerBuilder {
+    fn build(&self, nodes: Vec<Arc<Node>>) -> Arc<dyn Picker> {
+        let nodes: Vec<WeightedNode> = nodes
+            .into_iter()
+            .map(|node| WeightedNode {
+                weight: node.instance.weight.unwrap_or(DEFAULT_WEIGHT) as i64,
+                node,
+                current: AtomicI64::new(0),
+            })
+            .filter(|node| node.weight > 0)
+            .collect();
+        Arc::new(WeightedPicker { nodes })
+    }
+}
+
+/// 平滑加权轮询选一次, healthy_only 时跳过不健康的节点
+fn pick_from(nodes: &[WeightedNode], healthy_only: bool) -> Option<Arc<Node>> {
+    let mut total = 0;
+    let mut best: Option<(&WeightedNode, i64)> = None;
+    for node in nodes {
+        if healthy_only && !node.node.is_healthy() {
+            continue;
+        }
+        let current = node.current.fetch_add(node.weight, Ordering::Relaxed) + node.weight;
+        total += node.weight;
+        if best.is_none_or(|(_, best)| current > best) {
+            best = Some((node, current));
+        }
+    }
+    let (best, _) = best?;
+    best.current.fetch_sub(total, Ordering::Relaxed);
+    Some(best.node.clone())
+}
+
+impl Picker for WeightedPicker {
+    fn pick(&self, _request: &http::Request<BoxBody>) -> Option<Arc<Node>> {
+        // 都不健康的时候还是要选一个
+        pick_from(&self.nodes, true).or_else(|| pick_from(&self.nodes, false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ServiceInstance;
+
+    fn node(endpoint: &str, weight: u32) -> Arc<Node> {
+        let mut instance = ServiceInstance::new("Dev", "user.rpc", endpoint.to_owned());
+        instance.weight = Some(weight);
+        Node::for_test(instance)
+    }
+
+    fn pick(picker: &dyn Picker) -> String {
+        let request = http::Request::new(tonic::body::empty_body());
+        picker.pick(&request).unwrap().instance.endpoint.clone()
+    }
+
+    #[tokio::test]
+    async fn smooth_weighted() {
+        let nodes = vec![
+            node("127.0.0.1:1", 5),
+            node("127.0.0.1:2", 1),
+            node("127.0.0.1:3", 1),
+        ];
+        let picker = WeightedPickerBuilder.build(nodes);
+        let picks: Vec<String> = (0..7).map(|_| pick(picker.as_ref())).collect();
+        // 和 nginx 的一样, 权重大的不会连续选完
+        assert_eq!(
+            picks,
+            [1, 1, 2, 1, 3, 1, 1].map(|port| format!("127.0.0.1:{port}"))
+        );
+    }
+
+    #[tokio::test]
+    async fn distribute_by_weight() {
+        let nodes = vec![
+            node("127.0.0.1:1", 300),
+            node("127.0.0.1:2", 100),
+            node("127.0.0.1:3", 0),
+        ];
+        let picker = WeightedPickerBuilder.build(nodes);
+        let mut counts = std::collections::HashMap::new();
+        for _ in 0..400 {
+            *counts.entry(pick(picker.as_ref())).or_insert(0) += 1;
+        }
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts["127.0.0.1:1"], 300);
+        assert_eq!(counts["127.0.0.1:2"], 100);
+    }
+
+    #[tokio::test]
+    async fn skip_unhealthy() {
+        let nodes = vec![node("127.0.0.1:1", 300), node("127.0.0.1:2", 100)];
+        nodes[0].fail_for_test();
+        let picker = WeightedPickerBuilder.build(nodes.clone());
+        assert!((0..10).all(|_| pick(picker.as_ref()) == "127.0.0.1:2"));
+        // 都不健康的时候还是按权重选
+        nodes[1].fail_for_test();
+        assert_eq!(pick(picker.as_ref()), "127.0.0.1:1");
+    }
+}
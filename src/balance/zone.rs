@@ -0,0 +1,234 @@
+use crate::balance::{Node, Picker, PickerBuilder, DEFAULT_WEIGHT};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tonic::body::BoxBody;
+use tonic::codegen::http;
+
+const DEFAULT_SPILLOVER: f64 = 50.0;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ZoneConf {
+    // 调用方所在的可用区, 和实例注册的 zone 比较
+    #[serde(rename = "Name")]
+    pub name: String,
+    // 同可用区健康实例的权重占比(百分比)低于这个值时, 流量溢出到所有可用区
+    #[serde(rename = "Spillover")]
+    pub spillover: f64,
+}
+
+impl Default for ZoneConf {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            spillover: DEFAULT_SPILLOVER,
+        }
+    }
+}
+
+/// 优先把请求发给同可用区的实例, 同可用区健康的容量不够时才发给其他可用区
+pub struct ZonePickerBuilder {
+    zone_conf: ZoneConf,
+    inner: Arc<dyn PickerBuilder>,
+}
+
+impl ZonePickerBuilder {
+    pub fn new(zone_conf: ZoneConf, inner: Arc<dyn PickerBuilder>) -> Self {
+        Self { zone_conf, inner }
+    }
+}
+
+impl PickerBuilder for ZonePickerBuilder {
+    fn build(&self, nodes: Vec<Arc<Node>>) -> Arc<dyn Picker> {
+        let local: Vec<Arc<Node>> = nodes
+            .iter()
+            .filter(|node| node.instance.zone.as_ref() == Some(&self.zone_conf.name))
+            .cloned()
+            .collect();
+        let picker = ZonePicker {
+            spillover: self.zone_conf.spillover,
+            local_picker: self.inner.build(local.clone()),
+            local,
+            all: self.inner.build(nodes),
+            built_at: Instant::now(),
+            health_version: AtomicU64::new(0),
+            recover_at: AtomicU64::new(NEVER),
+            spill: AtomicBool::new(false),
+        };
+        picker.refresh(picker.get_health_version());
+        Arc::new(picker)
+    }
+}
+
+// recover_at 的值, 没有等着恢复的节点
+const NEVER: u64 = u64::MAX;
+
+struct ZonePicker {
+    spillover: f64,
+    local: Vec<Arc<Node>>,
+    local_picker: Arc<dyn Picker>,
+    all: Arc<dyn Picker>,
+    // 下面是缓存的同可用区健康统计, 节点的健康状态有变化或者有节点到了恢复的时间才重算
+    built_at: Instant,
+    // 计算时同可用区节点的 health_version 之和
+    health_version: AtomicU64,
+    // 最早恢复健康的节点的恢复时间, 相对 built_at 的纳秒
+    recover_at: AtomicU64,
+    // 同可用区健康的容量不够, 要溢出到所有可用区
+    spill: AtomicBool,
+}
+
+impl ZonePicker {
+    fn get_health_version(&self) -> u64 {
+        self.local
+            .iter()
+            .map(|node| node.state.get_health_version())
+            .fold(0, u64::wrapping_add)
+    }
+
+    /// 按同可用区健康实例的权重占比(百分比)决定要不要溢出, 同时记下最早恢复健康的时间
+    fn refresh(&self, health_version: u64) -> bool {
+        let weight = |node: &Arc<Node>| node.instance.weight.unwrap_or(DEFAULT_WEIGHT) as f64;
+        let (mut total, mut healthy) = (0.0, 0.0);
+        let mut recover_at: Option<Instant> = None;
+        for node in &self.local {
+            total += weight(node);
+            if !node.is_ready() {
+                continue;
+            }
+            match node.state.get_recover_at() {
+                None => healthy += weight(node),
+                Some(at) => recover_at = Some(recover_at.map_or(at, |min| min.min(at))),
+            }
+        }
+        let spill = total == 0.0 || healthy * 100.0 / total < self.spillover;
+        let recover_at = recover_at.map_or(NEVER, |at| {
+            at.saturating_duration_since(self.built_at).as_nanos() as u64
+        });
+        self.spill.store(spill, Ordering::Relaxed);
+        self.recover_at.store(recover_at, Ordering::Relaxed);
+        self.health_version.store(health_version, Ordering::Release);
+        spill
+    }
+
+    fn should_spill(&self) -> bool {
+        let health_version = self.get_health_version();
+        let recover_at = self.recover_at.load(Ordering::Relaxed);
+        if health_version != self.health_version.load(Ordering::Acquire)
+            || (recover_at != NEVER && self.built_at.elapsed().as_nanos() as u64 >= recover_at)
+        {
+            return self.refresh(health_version);
+        }
+        self.spill.load(Ordering::Relaxed)
+    }
+}
+
+impl Picker for ZonePicker {
+    fn pick(&self, request: &http::Request<BoxBody>) -> Option<Arc<Node>> {
+        if !self.should_spill() {
+            if let Some(node) = self.local_picker.pick(request) {
+                return Some(node);
+            }
+        }
+        self.all.pick(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balance::{WeightedPickerBuilder, UNHEALTHY_DURATION};
+    use crate::common::ServiceInstance;
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    // 1、2 在本可用区, 3 在别的可用区
+    fn nodes() -> Vec<Arc<Node>> {
+        [
+            ("127.0.0.1:1", "a"),
+            ("127.0.0.1:2", "a"),
+            ("127.0.0.1:3", "b"),
+        ]
+        .into_iter()
+        .map(|(endpoint, zone)| {
+            let mut instance = ServiceInstance::new("Dev", "user.rpc", endpoint.to_owned());
+            instance.zone = Some(zone.to_owned());
+            Node::for_test(instance)
+        })
+        .collect()
+    }
+
+    fn picker(spillover: f64, nodes: &[Arc<Node>]) -> Arc<dyn Picker> {
+        let zone_conf = ZoneConf {
+            name: "a".to_owned(),
+            spillover,
+        };
+        ZonePickerBuilder::new(zone_conf, Arc::new(WeightedPickerBuilder)).build(nodes.to_vec())
+    }
+
+    fn picked(picker: &dyn Picker) -> HashSet<String> {
+        let request = http::Request::new(tonic::body::empty_body());
+        (0..10)
+            .map(|_| picker.pick(&request).unwrap().instance.endpoint.clone())
+            .collect()
+    }
+
+    fn endpoints(endpoints: &[&str]) -> HashSet<String> {
+        endpoints
+            .iter()
+            .map(|endpoint| endpoint.to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn stay_local() {
+        let nodes = nodes();
+        let picker = picker(50.0, &nodes);
+        assert_eq!(
+            picked(picker.as_ref()),
+            endpoints(&["127.0.0.1:1", "127.0.0.1:2"])
+        );
+
+        // 还剩一半, 不低于 Spillover, 只发给本可用区健康的
+        nodes[0].fail_for_test();
+        assert_eq!(picked(picker.as_ref()), endpoints(&["127.0.0.1:2"]));
+    }
+
+    #[tokio::test]
+    async fn spillover() {
+        let nodes = nodes();
+        let picker = picker(60.0, &nodes);
+        assert_eq!(
+            picked(picker.as_ref()),
+            endpoints(&["127.0.0.1:1", "127.0.0.1:2"])
+        );
+
+        // 健康的只剩一半, 低于 Spillover, 溢出到所有可用区健康的节点
+        nodes[0].fail_for_test();
+        assert_eq!(
+            picked(picker.as_ref()),
+            endpoints(&["127.0.0.1:2", "127.0.0.1:3"])
+        );
+    }
+
+    #[tokio::test]
+    async fn recover_without_change() {
+        let nodes = nodes();
+        let picker = picker(50.0, &nodes);
+        nodes[0].fail_for_test();
+        nodes[1].fail_for_test();
+        assert_eq!(picked(picker.as_ref()), endpoints(&["127.0.0.1:3"]));
+
+        // 1 重新连上, 但是失败的时间还没过去, 还不算健康
+        let state = &nodes[0].state;
+        *state.failed_at.lock().unwrap() =
+            Some(Instant::now() - UNHEALTHY_DURATION + Duration::from_millis(100));
+        state.connected(nodes[0].instance.to_endpoint().unwrap().connect_lazy());
+        assert_eq!(picked(picker.as_ref()), endpoints(&["127.0.0.1:3"]));
+
+        // 到了恢复的时间, 没有别的变化也会重新计算
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(picked(picker.as_ref()), endpoints(&["127.0.0.1:1"]));
+    }
+}
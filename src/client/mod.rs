@@ -1,37 +1,199 @@
-use crate::discovery::Discovery;
-use tonic::transport::Channel;
+use crate::balance::{
+    BalanceChannel, BalancePolicy, RouteConf, RoutePickerBuilder, RouteTable, ZoneConf,
+    ZonePickerBuilder,
+};
+use crate::discovery::{ConfDiscovery, Discovery, DnsConf, FileConf, StaticDiscovery};
+use crate::error::ZrpcError;
+use crate::etcd::discovery::EtcdDiscovery;
+use crate::etcd::route::watch_route_rules;
+use crate::etcd::EtcdConf;
+use crate::hedge::HedgeConf;
+use crate::retry::RetryConf;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tool::log::trace_log::error;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ClientConf {
+    #[serde(rename = "Model")]
+    pub model: String,
+    // 下面几种服务发现按 Endpoints > File > Dns > Etcd 的优先级选一个
+    // 服务名 -> 地址列表, 比如 `Dev/user.rpc: ["127.0.0.1:50051"]`
+    #[serde(
+        rename = "Endpoints",
+        default,
+        skip_serializing_if = "HashMap::is_empty"
+    )]
+    pub endpoints: HashMap<String, Vec<String>>,
+    #[serde(rename = "File", skip_serializing_if = "Option::is_none")]
+    pub file_conf: Option<FileConf>,
+    #[serde(rename = "Dns", skip_serializing_if = "Option::is_none")]
+    pub dns_conf: Option<DnsConf>,
+    #[serde(rename = "Etcd", skip_serializing_if = "Option::is_none")]
+    pub etcd_conf: Option<EtcdConf>,
+    // 按版本、标签路由的规则, 不配置就是所有实例一个池子
+    #[serde(rename = "Route", skip_serializing_if = "Option::is_none")]
+    pub route_conf: Option<RouteConf>,
+    // 调用方所在的可用区, 配置了就优先调用同可用区的实例
+    #[serde(rename = "Zone", skip_serializing_if = "Option::is_none")]
+    pub zone_conf: Option<ZoneConf>,
+    // 重试策略, 用 `ClientRetry::from` 加到客户端的 ServiceBuilder 里
+    #[serde(rename = "Retry", skip_serializing_if = "Option::is_none")]
+    pub retry_conf: Option<RetryConf>,
+    // 对冲请求, 用 `ClientHedge::from` 加到客户端的 ServiceBuilder 里
+    #[serde(rename = "Hedge", skip_serializing_if = "Option::is_none")]
+    pub hedge_conf: Option<HedgeConf>,
+}
+
+impl ClientConf {
+    pub async fn new_discovery(&self) -> Result<ConfDiscovery, ZrpcError> {
+        if !self.endpoints.is_empty() {
+            return Ok(ConfDiscovery::Static(StaticDiscovery::new(
+                self.endpoints.clone(),
+            )));
+        }
+        if let Some(file_conf) = &self.file_conf {
+            return Ok(ConfDiscovery::File(file_conf.into()));
+        }
+        if let Some(dns_conf) = &self.dns_conf {
+            return Ok(ConfDiscovery::Dns(dns_conf.into()));
+        }
+        if let Some(etcd_conf) = &self.etcd_conf {
+            let etcd_client = etcd_conf.new_etcd_client().await?;
+            return Ok(ConfDiscovery::Etcd(Box::new(EtcdDiscovery::new(
+                etcd_client,
+            ))));
+        }
+        Err(ZrpcError::DiscoveryError(
+            "one of Endpoints, File, Dns or Etcd must be configured".to_owned(),
+        ))
+    }
+
+    /// 没有配置 Route 时返回 None. 配置了 EtcdKey 的会先从 etcd 加载一次规则, 之后 watch 热更新,
+    /// 返回的 RouteTable 全部被丢弃后停止 watch
+    pub async fn new_route_table(&self) -> Result<Option<RouteTable>, ZrpcError> {
+        let Some(route_conf) = &self.route_conf else {
+            return Ok(None);
+        };
+        let mut route_table = RouteTable::new(route_conf.rules.clone());
+        if let Some(etcd_key) = &route_conf.etcd_key {
+            let etcd_conf = self.etcd_conf.as_ref().ok_or_else(|| {
+                ZrpcError::DiscoveryError("Route.EtcdKey requires Etcd to be configured".to_owned())
+            })?;
+            let etcd_client = etcd_conf.new_etcd_client().await?;
+            route_table = watch_route_rules(etcd_client, etcd_key, route_table).await?;
+        }
+        Ok(Some(route_table))
+    }
+}
 
 pub struct Client<D> {
     discovery: D,
     balance_channel_capacity: usize,
+    balance_policy: BalancePolicy,
+    route_table: Option<RouteTable>,
+    zone_conf: Option<ZoneConf>,
 }
 
 impl<D> Client<D>
 where
-    D: Discovery + Send + 'static,
+    D: Discovery + Clone + Send + 'static,
 {
     pub fn new(discovery: D, balance_channel_capacity: usize) -> Client<D> {
+        Self::new_with_policy(
+            discovery,
+            balance_channel_capacity,
+            BalancePolicy::default(),
+        )
+    }
+
+    pub fn new_with_policy(
+        discovery: D,
+        balance_channel_capacity: usize,
+        balance_policy: BalancePolicy,
+    ) -> Client<D> {
         Client {
             discovery,
             balance_channel_capacity,
+            balance_policy,
+            route_table: None,
+            zone_conf: None,
         }
     }
 
-    pub async fn new_balance_client<S, F>(self, f: F) -> S
+    /// 按路由规则把实例分组, 命中规则的请求发给对应的实例, 其他的发给稳定的实例. 不能和 `BalancePolicy::P2c` 一起用
+    pub fn with_route_table(mut self, route_table: RouteTable) -> Client<D> {
+        self.route_table = Some(route_table);
+        self
+    }
+
+    /// 优先调用同可用区的实例, 在路由规则分好的每组实例里面生效. 不能和 `BalancePolicy::P2c` 一起用
+    pub fn with_zone(mut self, zone_conf: ZoneConf) -> Client<D> {
+        self.zone_conf = Some(zone_conf);
+        self
+    }
+
+    /// target 可以是 `namespace/server_name`, 也可以是 `etcd:///namespace/server_name`.
+    /// 使用 DnsDiscovery 时是 `host:port` 或者 `dns:///host:port`.
+    /// 按 balance_policy 选节点, 实例信息的变化通过 watch 更新, 地址不变就不会重建连接
+    pub async fn new_balance_client<S, F>(
+        &self,
+        target: impl AsRef<str>,
+        f: F,
+    ) -> Result<S, ZrpcError>
     where
-        F: Fn(Channel) -> S,
+        F: Fn(BalanceChannel) -> S,
     {
-        let Self {
-            mut discovery,
-            balance_channel_capacity,
-        } = self;
-        let (channel, sender) = Channel::balance_channel(balance_channel_capacity);
-        discovery
-            .get_server("Dev168/test.rpc", sender.clone())
-            .await;
+        let service_name = service_name_from_target(target.as_ref()).to_owned();
+        let mut discovery = self.discovery.clone();
+        let (channel, mut balancer) = match &self.balance_policy {
+            BalancePolicy::P2c(load) => {
+                // tower 的 p2c 自己持有全部节点, 没法在它前面按路由规则、可用区分组
+                if self.route_table.is_some() || self.zone_conf.is_some() {
+                    return Err(ZrpcError::DiscoveryError(
+                        "P2c cannot be combined with Route or Zone".to_owned(),
+                    ));
+                }
+                BalanceChannel::p2c(*load)
+            }
+            policy => {
+                let mut picker_builder = policy.picker_builder();
+                if let Some(zone_conf) = &self.zone_conf {
+                    picker_builder =
+                        Arc::new(ZonePickerBuilder::new(zone_conf.clone(), picker_builder));
+                }
+                if let Some(route_table) = &self.route_table {
+                    picker_builder =
+                        Arc::new(RoutePickerBuilder::new(route_table.clone(), picker_builder));
+                }
+                BalanceChannel::new(picker_builder)
+            }
+        };
+        // 先等全量的实例加载进负载均衡, 返回的客户端马上就能用
+        let (sender, receiver) = mpsc::channel(self.balance_channel_capacity);
+        let (result, _) = tokio::join!(
+            discovery.get_server(&service_name, sender),
+            balancer.sync(receiver)
+        );
+        result?;
+        let (sender, receiver) = mpsc::channel(self.balance_channel_capacity);
+        tokio::spawn(balancer.run(receiver));
         tokio::spawn(async move {
-            discovery.watch("Dev168/test.rpc", sender).await;
+            if let Err(err) = discovery.watch(&service_name, sender).await {
+                error!("watch {} failed: {}", service_name, err);
+            }
         });
-        f(channel)
+        Ok(f(channel))
+    }
+}
+
+/// 从 target 中解析出服务名, 也就是 ServiceInstance 中的 name
+fn service_name_from_target(target: &str) -> &str {
+    match target.split_once("://") {
+        // scheme://authority/service_name, authority 目前没有用到, 直接跳过
+        Some((_, rest)) => rest.split_once('/').map_or(rest, |(_, path)| path),
+        None => target,
     }
+    .trim_matches('/')
 }
@@ -1,8 +1,14 @@
 use crate::etcd::register::ServerConf;
 use chrono::Local;
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
+use tonic::transport::Endpoint;
 use uuid::Uuid;
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct ServiceInstance {
     #[serde(rename = "name")]
     pub name: String,
@@ -11,7 +17,19 @@ pub struct ServiceInstance {
     pub key: String,
     #[serde(rename = "endpoint")]
     pub endpoint: String,
-    // TODO: 后续可以考虑加入权重、版本等信息
+    // 下面都是可选的, 没有的时候不序列化, 兼容老版本的注册信息
+    #[serde(rename = "weight", default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+    #[serde(rename = "version", default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(rename = "zone", default, skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+    #[serde(
+        rename = "metadata",
+        default,
+        skip_serializing_if = "HashMap::is_empty"
+    )]
+    pub metadata: HashMap<String, String>,
 }
 
 impl AsRef<ServiceInstance> for ServiceInstance {
@@ -36,16 +54,44 @@ impl ServiceInstance {
                 Uuid::new_v4()
             ),
             endpoint,
+            ..Default::default()
         }
     }
+
+    /// 客户端连接这个实例用的 Endpoint
+    pub fn to_endpoint(&self) -> Result<Endpoint, tonic::transport::Error> {
+        Endpoint::from_str(format!("http://{}", self.endpoint).as_str())
+    }
 }
 
 impl From<&ServerConf> for ServiceInstance {
     fn from(value: &ServerConf) -> Self {
-        Self::new(
-            value.get_model(),
-            value.get_server_name(),
-            value.get_endpoint().to_owned(),
-        )
+        Self {
+            weight: value.get_weight(),
+            version: value.get_version().map(ToOwned::to_owned),
+            zone: value.get_zone().map(ToOwned::to_owned),
+            metadata: value.get_metadata().clone(),
+            ..Self::new(
+                value.get_model(),
+                value.get_server_name(),
+                value.get_endpoint().to_owned(),
+            )
+        }
+    }
+}
+
+/// [0, n) 之间的随机数, 负载均衡和熔断用的, 不需要密码学安全
+pub(crate) fn random(n: usize) -> usize {
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
     }
+    STATE.with(|state| {
+        // xorshift64
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        (x % n as u64) as usize
+    })
 }
@@ -0,0 +1,172 @@
+use crate::common::ServiceInstance;
+use crate::discovery::{Discovery, PushedEndpoints};
+use crate::error::ZrpcError;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+use tool::log::trace_log::{error, info};
+use tower::discover::Change;
+
+const DEFAULT_INTERVAL: u64 = 30000;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct DnsConf {
+    // 重新解析的间隔, 毫秒
+    #[serde(rename = "Interval")]
+    pub interval: u64,
+}
+
+impl Default for DnsConf {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_INTERVAL,
+        }
+    }
+}
+
+/// 基于 DNS 的服务发现, 服务名就是要解析的 `host:port`, 比如 k8s 的 headless service.
+/// 定时重新解析, 把 A/AAAA 记录的变化推给负载均衡
+#[derive(Debug, Clone)]
+pub struct DnsDiscovery {
+    interval: Duration,
+    endpoints: PushedEndpoints,
+}
+
+impl DnsDiscovery {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            endpoints: PushedEndpoints::default(),
+        }
+    }
+
+    /// 解析一次, 推送变化
+    async fn resolve(
+        &mut self,
+        service_name: &str,
+        sender: &Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError> {
+        let addrs = tokio::net::lookup_host(service_name).await?.collect();
+        self.update(service_name, addrs, sender).await
+    }
+
+    /// 和上次的结果做对比, 推送地址的变化
+    async fn update(
+        &mut self,
+        service_name: &str,
+        addrs: HashSet<SocketAddr>,
+        sender: &Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError> {
+        let instances = addrs
+            .into_iter()
+            .map(|addr| ServiceInstance {
+                name: service_name.to_owned(),
+                key: addr.to_string(),
+                endpoint: addr.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        self.endpoints.sync(instances, sender).await
+    }
+}
+
+impl From<&DnsConf> for DnsDiscovery {
+    fn from(value: &DnsConf) -> Self {
+        Self::new(Duration::from_millis(value.interval))
+    }
+}
+
+#[tonic::async_trait]
+impl Discovery for DnsDiscovery {
+    async fn get_server(
+        &mut self,
+        service_name: &str,
+        sender: Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError> {
+        self.resolve(service_name, &sender).await
+    }
+
+    async fn watch(
+        &mut self,
+        service_name: &str,
+        sender: Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError> {
+        loop {
+            tokio::select! {
+                _ = sender.closed() => {
+                    info!("dns watch server exit");
+                    return Ok(());
+                }
+                _ = tokio::time::sleep(self.interval) => {}
+            }
+            // 解析失败的时候保留上次的结果, 不要把地址全删了
+            if let Err(err) = self.resolve(service_name, &sender).await {
+                if sender.is_closed() {
+                    continue;
+                }
+                error!("dns resolve {} failed: {}", service_name, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::{self, Receiver};
+
+    fn addrs(ports: &[u16]) -> HashSet<SocketAddr> {
+        ports
+            .iter()
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], *port)))
+            .collect()
+    }
+
+    fn changes(receiver: &mut Receiver<Change<String, ServiceInstance>>) -> Vec<String> {
+        let mut changes = vec![];
+        while let Ok(change) = receiver.try_recv() {
+            changes.push(match change {
+                Change::Insert(key, _) => format!("+{key}"),
+                Change::Remove(key) => format!("-{key}"),
+            });
+        }
+        changes.sort();
+        changes
+    }
+
+    #[tokio::test]
+    async fn diff_addrs() {
+        let mut discovery = DnsDiscovery::new(Duration::from_secs(1));
+        let (sender, mut receiver) = mpsc::channel(16);
+        let name = "user.rpc:50051";
+        discovery
+            .update(name, addrs(&[1, 2]), &sender)
+            .await
+            .unwrap();
+        assert_eq!(changes(&mut receiver), ["+127.0.0.1:1", "+127.0.0.1:2"]);
+        // 没变化的不推送
+        discovery
+            .update(name, addrs(&[1, 2]), &sender)
+            .await
+            .unwrap();
+        assert!(changes(&mut receiver).is_empty());
+        discovery
+            .update(name, addrs(&[2, 3]), &sender)
+            .await
+            .unwrap();
+        assert_eq!(changes(&mut receiver), ["+127.0.0.1:3", "-127.0.0.1:1"]);
+    }
+
+    #[tokio::test]
+    async fn resolve_ip() {
+        let mut discovery = DnsDiscovery::new(Duration::from_secs(1));
+        let (sender, mut receiver) = mpsc::channel(16);
+        discovery
+            .get_server("127.0.0.1:50051", sender)
+            .await
+            .unwrap();
+        assert_eq!(changes(&mut receiver), ["+127.0.0.1:50051"]);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::common::ServiceInstance;
+use crate::discovery::{Discovery, PushedEndpoints};
+use crate::error::ZrpcError;
+use anyhow::anyhow;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+use tool::log::trace_log::{error, info};
+use tower::discover::Change;
+
+const DEFAULT_INTERVAL: u64 = 1000;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct FileConf {
+    // .json 结尾的按 json 解析, 其他的按 yaml 解析
+    #[serde(rename = "Path")]
+    pub path: String,
+    // 检查文件是否变更的间隔, 毫秒
+    #[serde(rename = "Interval", default = "default_interval")]
+    pub interval: u64,
+}
+
+fn default_interval() -> u64 {
+    DEFAULT_INTERVAL
+}
+
+/// 从文件读取服务实例的服务发现, 文件内容是 ServiceInstance 的列表.
+/// 定时读取文件, 内容变了就重新解析, 把实例的变化推给负载均衡. 比较的是内容而不是修改时间,
+/// 修改时间的精度不够, 同一个时间单位里改了两次会漏掉
+#[derive(Debug, Clone)]
+pub struct FileDiscovery {
+    path: PathBuf,
+    interval: Duration,
+    // 上次加载的文件内容的哈希
+    digest: Option<u64>,
+    endpoints: PushedEndpoints,
+}
+
+impl FileDiscovery {
+    pub fn new(path: impl Into<PathBuf>, interval: Duration) -> Self {
+        Self {
+            path: path.into(),
+            interval,
+            digest: None,
+            endpoints: PushedEndpoints::default(),
+        }
+    }
+
+    fn parse(&self, data: &[u8]) -> anyhow::Result<Vec<ServiceInstance>> {
+        let instances = if self.path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_slice(data)
+                .map_err(|e| anyhow!("parse {:?} failed: {e}", self.path))?
+        } else {
+            serde_yaml::from_slice(data)
+                .map_err(|e| anyhow!("parse {:?} failed: {e}", self.path))?
+        };
+        Ok(instances)
+    }
+
+    /// 文件内容有变更就重新加载, 推送实例的变化
+    async fn reload(
+        &mut self,
+        service_name: &str,
+        sender: &Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError> {
+        let data = tokio::fs::read(&self.path).await?;
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        let digest = hasher.finish();
+        if self.digest == Some(digest) {
+            return Ok(());
+        }
+        let instances = self
+            .parse(&data)?
+            .into_iter()
+            .filter(|instance| instance.name == service_name)
+            .collect();
+        self.digest = Some(digest);
+        self.endpoints.sync(instances, sender).await
+    }
+}
+
+impl From<&FileConf> for FileDiscovery {
+    fn from(value: &FileConf) -> Self {
+        Self::new(&value.path, Duration::from_millis(value.interval))
+    }
+}
+
+#[tonic::async_trait]
+impl Discovery for FileDiscovery {
+    async fn get_server(
+        &mut self,
+        service_name: &str,
+        sender: Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError> {
+        self.reload(service_name, &sender).await
+    }
+
+    async fn watch(
+        &mut self,
+        service_name: &str,
+        sender: Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError> {
+        loop {
+            tokio::select! {
+                _ = sender.closed() => {
+                    info!("file watch server exit");
+                    return Ok(());
+                }
+                _ = tokio::time::sleep(self.interval) => {}
+            }
+            // 文件读取或者解析失败的时候保留上次的结果, 等下次修改
+            if let Err(err) = self.reload(service_name, &sender).await {
+                if sender.is_closed() {
+                    continue;
+                }
+                error!("reload {:?} failed: {}", self.path, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::{self, Receiver};
+
+    /// 删掉测试用的临时文件
+    struct TempFile(PathBuf);
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn instance(key: &str, weight: u32) -> String {
+        format!("- {{name: Dev/user.rpc, key: {key}, endpoint: '127.0.0.1:{weight}', weight: {weight}}}\n")
+    }
+
+    fn changes(receiver: &mut Receiver<Change<String, ServiceInstance>>) -> Vec<String> {
+        let mut changes = vec![];
+        while let Ok(change) = receiver.try_recv() {
+            changes.push(match change {
+                Change::Insert(key, instance) => format!("+{key}:{}", instance.weight.unwrap()),
+                Change::Remove(key) => format!("-{key}"),
+            });
+        }
+        changes.sort();
+        changes
+    }
+
+    #[tokio::test]
+    async fn reload_changes() {
+        let file =
+            TempFile(std::env::temp_dir().join(format!("zrpc-{}.yaml", uuid::Uuid::new_v4())));
+        let other = "- {name: Dev/order.rpc, key: c, endpoint: '127.0.0.1:9'}\n";
+        std::fs::write(&file.0, instance("a", 1) + &instance("b", 2) + other).unwrap();
+        let mut discovery = FileDiscovery::new(&file.0, Duration::from_secs(1));
+        let (sender, mut receiver) = mpsc::channel(16);
+        discovery
+            .get_server("Dev/user.rpc", sender.clone())
+            .await
+            .unwrap();
+        // 别的服务的实例不推送
+        assert_eq!(changes(&mut receiver), ["+a:1", "+b:2"]);
+
+        // 内容没变不推送
+        discovery.reload("Dev/user.rpc", &sender).await.unwrap();
+        assert!(changes(&mut receiver).is_empty());
+
+        // 修改 a, 删除 b, 新增 d. 长度不变、马上改的也能发现
+        std::fs::write(&file.0, instance("a", 3) + &instance("d", 4) + other).unwrap();
+        discovery.reload("Dev/user.rpc", &sender).await.unwrap();
+        assert_eq!(changes(&mut receiver), ["+a:3", "+d:4", "-b"]);
+
+        std::fs::write(&file.0, instance("d", 4)).unwrap();
+        discovery.reload("Dev/user.rpc", &sender).await.unwrap();
+        assert_eq!(changes(&mut receiver), ["-a"]);
+    }
+
+    #[tokio::test]
+    async fn keep_on_error() {
+        let file =
+            TempFile(std::env::temp_dir().join(format!("zrpc-{}.json", uuid::Uuid::new_v4())));
+        std::fs::write(
+            &file.0,
+            r#"[{"name": "Dev/user.rpc", "key": "a", "endpoint": "127.0.0.1:1", "weight": 1}]"#,
+        )
+        .unwrap();
+        let mut discovery = FileDiscovery::new(&file.0, Duration::from_millis(10));
+        let (sender, mut receiver) = mpsc::channel(16);
+        discovery
+            .get_server("Dev/user.rpc", sender.clone())
+            .await
+            .unwrap();
+        assert_eq!(changes(&mut receiver), ["+a:1"]);
+
+        // 解析失败或者文件没了都保留上次的结果
+        std::fs::write(&file.0, "not json").unwrap();
+        assert!(discovery.reload("Dev/user.rpc", &sender).await.is_err());
+        std::fs::remove_file(&file.0).unwrap();
+        assert!(discovery.reload("Dev/user.rpc", &sender).await.is_err());
+        assert!(changes(&mut receiver).is_empty());
+
+        // watch 里读到新的文件再推送
+        tokio::spawn(async move { discovery.watch("Dev/user.rpc", sender).await });
+        std::fs::write(&file.0, "[]").unwrap();
+        let change = tokio::time::timeout(Duration::from_secs(1), receiver.recv()).await;
+        assert!(matches!(change, Ok(Some(Change::Remove(key))) if key == "a"));
+    }
+}
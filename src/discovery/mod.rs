@@ -1,9 +1,125 @@
+mod dns;
+mod file;
+mod static_list;
+
+pub use dns::{DnsConf, DnsDiscovery};
+pub use file::{FileConf, FileDiscovery};
+pub use static_list::StaticDiscovery;
+
+use crate::common::ServiceInstance;
+use crate::error::ZrpcError;
+use crate::etcd::discovery::EtcdDiscovery;
+use std::collections::HashMap;
 use tokio::sync::mpsc::Sender;
-use tonic::transport::Endpoint;
 use tower::discover::Change;
 
+/// 服务发现把实例的变化推到 sender 里, 由客户端的负载均衡转换成节点
 #[tonic::async_trait]
 pub trait Discovery {
-    async fn get_server(&mut self, service_name: &str, sender: Sender<Change<String, Endpoint>>);
-    async fn watch(&mut self, service_name: &str, sender: Sender<Change<String, Endpoint>>);
+    async fn get_server(
+        &mut self,
+        service_name: &str,
+        sender: Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError>;
+    async fn watch(
+        &mut self,
+        service_name: &str,
+        sender: Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError>;
+}
+
+/// 已经推送给负载均衡的实例. 全量拉取之后和它做对比, 只推送变化
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PushedEndpoints(HashMap<String, ServiceInstance>);
+
+impl PushedEndpoints {
+    /// 新增或者变更了才推送
+    pub(crate) async fn insert(
+        &mut self,
+        instance: ServiceInstance,
+        sender: &Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError> {
+        if self.0.get(&instance.key) == Some(&instance) {
+            return Ok(());
+        }
+        sender
+            .send(Change::Insert(instance.key.clone(), instance.clone()))
+            .await
+            .map_err(|_| ZrpcError::channel_closed())?;
+        self.0.insert(instance.key.clone(), instance);
+        Ok(())
+    }
+
+    pub(crate) async fn remove(
+        &mut self,
+        key: &str,
+        sender: &Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError> {
+        if self.0.remove(key).is_some() {
+            sender
+                .send(Change::Remove(key.to_owned()))
+                .await
+                .map_err(|_| ZrpcError::channel_closed())?;
+        }
+        Ok(())
+    }
+
+    /// 消失了的删掉, 新增或者变更了的插入
+    pub(crate) async fn sync(
+        &mut self,
+        instances: Vec<ServiceInstance>,
+        sender: &Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError> {
+        let vanished: Vec<String> = self
+            .0
+            .keys()
+            .filter(|key| instances.iter().all(|instance| &instance.key != *key))
+            .cloned()
+            .collect();
+        for key in vanished {
+            self.remove(&key, sender).await?;
+        }
+        for instance in instances {
+            self.insert(instance, sender).await?;
+        }
+        Ok(())
+    }
+}
+
+/// 根据 ClientConf 选出来的服务发现
+#[derive(Clone)]
+pub enum ConfDiscovery {
+    Etcd(Box<EtcdDiscovery>),
+    Static(StaticDiscovery),
+    File(FileDiscovery),
+    Dns(DnsDiscovery),
+}
+
+#[tonic::async_trait]
+impl Discovery for ConfDiscovery {
+    async fn get_server(
+        &mut self,
+        service_name: &str,
+        sender: Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError> {
+        match self {
+            ConfDiscovery::Etcd(discovery) => discovery.get_server(service_name, sender).await,
+            ConfDiscovery::Static(discovery) => discovery.get_server(service_name, sender).await,
+            ConfDiscovery::File(discovery) => discovery.get_server(service_name, sender).await,
+            ConfDiscovery::Dns(discovery) => discovery.get_server(service_name, sender).await,
+        }
+    }
+
+    async fn watch(
+        &mut self,
+        service_name: &str,
+        sender: Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError> {
+        match self {
+            ConfDiscovery::Etcd(discovery) => discovery.watch(service_name, sender).await,
+            ConfDiscovery::Static(discovery) => discovery.watch(service_name, sender).await,
+            ConfDiscovery::File(discovery) => discovery.watch(service_name, sender).await,
+            ConfDiscovery::Dns(discovery) => discovery.watch(service_name, sender).await,
+        }
+    }
 }
@@ -0,0 +1,97 @@
+use crate::common::ServiceInstance;
+use crate::discovery::Discovery;
+use crate::error::ZrpcError;
+use std::collections::HashMap;
+use tokio::sync::mpsc::Sender;
+use tool::log::trace_log::info;
+use tower::discover::Change;
+
+/// 固定地址列表的服务发现, 用于本地开发、docker-compose 之类的场景.
+/// 按服务名配置地址, 没有配置的服务返回错误, 不会把别的服务的地址给出去
+#[derive(Debug, Clone)]
+pub struct StaticDiscovery {
+    // 服务名 -> 地址列表
+    endpoints: HashMap<String, Vec<String>>,
+}
+
+impl StaticDiscovery {
+    pub fn new(endpoints: HashMap<String, Vec<String>>) -> Self {
+        Self { endpoints }
+    }
+}
+
+#[tonic::async_trait]
+impl Discovery for StaticDiscovery {
+    async fn get_server(
+        &mut self,
+        service_name: &str,
+        sender: Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError> {
+        let endpoints = self.endpoints.get(service_name).ok_or_else(|| {
+            ZrpcError::DiscoveryError(format!("no endpoints configured for {service_name}"))
+        })?;
+        for endpoint in endpoints {
+            let server_instance = ServiceInstance {
+                name: service_name.to_owned(),
+                key: endpoint.clone(),
+                endpoint: endpoint.clone(),
+                ..Default::default()
+            };
+            sender
+                .send(Change::Insert(endpoint.clone(), server_instance))
+                .await
+                .map_err(|_| ZrpcError::channel_closed())?;
+        }
+        Ok(())
+    }
+
+    async fn watch(
+        &mut self,
+        _service_name: &str,
+        sender: Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError> {
+        // 地址列表不会变, 等客户端退出就行
+        sender.closed().await;
+        info!("static watch server exit");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn keyed_by_service_name() {
+        let mut discovery = StaticDiscovery::new(HashMap::from([
+            (
+                "Dev/user.rpc".to_owned(),
+                vec!["127.0.0.1:1".to_owned(), "127.0.0.1:2".to_owned()],
+            ),
+            ("Dev/order.rpc".to_owned(), vec!["127.0.0.1:3".to_owned()]),
+        ]));
+        let (sender, mut receiver) = mpsc::channel(16);
+        discovery.get_server("Dev/user.rpc", sender).await.unwrap();
+        let mut keys = vec![];
+        while let Some(change) = receiver.recv().await {
+            let Change::Insert(key, instance) = change else {
+                panic!("unexpected remove");
+            };
+            assert_eq!(instance.name, "Dev/user.rpc");
+            keys.push(key);
+        }
+        assert_eq!(keys, ["127.0.0.1:1", "127.0.0.1:2"]);
+    }
+
+    #[tokio::test]
+    async fn unknown_service() {
+        let mut discovery = StaticDiscovery::new(HashMap::from([(
+            "Dev/user.rpc".to_owned(),
+            vec!["127.0.0.1:1".to_owned()],
+        )]));
+        let (sender, mut receiver) = mpsc::channel(16);
+        assert!(discovery.get_server("Dev/order.rpc", sender).await.is_err());
+        assert!(receiver.recv().await.is_none());
+    }
+}
@@ -4,6 +4,23 @@ pub enum ZrpcError {
     SerdeError(#[from] serde_json::Error),
     #[error("ETCD Error: {0}")]
     EtcdError(#[from] etcd_client::Error),
+    #[error("Discovery Error: {0}")]
+    DiscoveryError(String),
+    #[error("Register Error: {0}")]
+    RegisterError(String),
+    #[error("Addr Parse Error: {0}")]
+    AddrParseError(#[from] std::net::AddrParseError),
+    #[error("IO Error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Transport Error: {0}")]
+    TransportError(#[from] tonic::transport::Error),
     #[error(transparent)]
     Unexpected(#[from] anyhow::Error),
 }
+
+impl ZrpcError {
+    /// 负载均衡那边的接收端已经关掉了, 服务发现可以退出了
+    pub fn channel_closed() -> Self {
+        Self::DiscoveryError("balance channel closed".to_owned())
+    }
+}
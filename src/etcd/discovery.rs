@@ -1,123 +1,203 @@
+pub use crate::client::ClientConf;
 use crate::common::ServiceInstance;
-use crate::discovery::Discovery;
-use crate::etcd::EtcdConf;
+use crate::discovery::{Discovery, PushedEndpoints};
+use crate::error::ZrpcError;
+use crate::etcd::{MAX_BACKOFF, MIN_BACKOFF};
 use etcd_client::{Client, EventType, GetOptions, KeyValue, WatchOptions, Watcher};
-use std::str::FromStr;
+use std::time::Instant;
 use tokio::sync::mpsc::Sender;
-use tonic::transport::Endpoint;
 use tool::log::trace_log::{error, info};
 use tower::discover::Change;
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
-pub struct ClientConf {
-    #[serde(rename = "Model")]
-    pub model: String,
-    #[serde(rename = "Etcd")]
-    pub etcd_conf: EtcdConf,
+struct WatcherWrapper(Option<Watcher>);
+
+impl Drop for WatcherWrapper {
+    fn drop(&mut self) {
+        if let Some(mut watcher) = self.0.take() {
+            if let Ok(handler) = tokio::runtime::Handle::try_current() {
+                handler.spawn(async move {
+                    // 取消失败了也无所谓, 反正客户端都挂了
+                    watcher.cancel().await.unwrap_or_default();
+                });
+            }
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct EtcdDiscovery {
     etcd_client: Client,
+    endpoints: PushedEndpoints,
+    // 最后看到的 revision, 断线重连后从这里继续 watch
+    revision: i64,
 }
 
 impl EtcdDiscovery {
+    /// key 的格式是 `namespace/server_name/timestamp/uuid`, 带上 `/` 避免前缀匹配到其他服务
+    fn key_prefix(service_name: &str) -> String {
+        format!("{}/", service_name)
+    }
+
+    /// 解析注册信息, 不是这个服务的或者解析失败的返回 None
+    fn parse(key_value: &KeyValue, service_name: &str) -> Option<ServiceInstance> {
+        match serde_json::from_slice::<ServiceInstance>(key_value.value()) {
+            // 如果元信息的服务名不匹配，则跳过
+            Ok(server_instance) => {
+                (server_instance.name == service_name).then_some(server_instance)
+            }
+            Err(_) => {
+                error!(
+                    "invalid service instance: {}",
+                    String::from_utf8_lossy(key_value.value())
+                );
+                None
+            }
+        }
+    }
+
     async fn load_balance(
-        &self,
+        &mut self,
         event_type: EventType,
         key_value: &KeyValue,
         service_name: &str,
-        sender: &Sender<Change<String, Endpoint>>,
-    ) {
+        sender: &Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError> {
         match event_type {
             EventType::Put => {
-                if let Ok(ServiceInstance {
-                    name,
-                    key,
-                    endpoint,
-                }) = serde_json::from_slice::<ServiceInstance>(key_value.value())
-                {
-                    // 如果元信息的服务名不匹配，则跳过
-                    if name != service_name {
-                        return;
-                    }
-                    if let Ok(endpoint) =
-                        Endpoint::from_str(format!("http://{}", endpoint).as_str())
-                    {
-                        sender.send(Change::Insert(key, endpoint)).await.unwrap();
-                    } else {
-                        error!("invalid endpoint: {}", endpoint)
-                    }
-                } else {
-                    error!(
-                        "invalid service instance: {}",
-                        String::from_utf8_lossy(key_value.value())
-                    );
+                if let Some(server_instance) = Self::parse(key_value, service_name) {
+                    self.endpoints.insert(server_instance, sender).await?;
                 }
             }
             EventType::Delete => {
                 if let Ok(key) = key_value.key_str() {
-                    sender.send(Change::Remove(key.to_owned())).await.unwrap()
+                    self.endpoints.remove(key, sender).await?;
                 }
             }
         }
+        Ok(())
     }
-}
 
-#[tonic::async_trait]
-impl Discovery for EtcdDiscovery {
-    async fn get_server(&mut self, service_name: &str, sender: Sender<Change<String, Endpoint>>) {
+    /// 全量拉取一次服务列表, 推送和上次的差别
+    async fn list(
+        &mut self,
+        service_name: &str,
+        sender: &Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError> {
         let options = GetOptions::new().with_prefix();
         let response = self
             .etcd_client
-            .get(service_name, Some(options))
-            .await
-            .expect("etcd get server failed");
-        for kv in response.kvs() {
-            self.load_balance(EventType::Put, kv, service_name, &sender)
-                .await;
+            .get(Self::key_prefix(service_name), Some(options))
+            .await?;
+        let instances = response
+            .kvs()
+            .iter()
+            .filter_map(|kv| Self::parse(kv, service_name))
+            .collect();
+        self.endpoints.sync(instances, sender).await?;
+        if let Some(header) = response.header() {
+            self.revision = header.revision();
         }
+        Ok(())
     }
 
-    async fn watch(&mut self, service_name: &str, sender: Sender<Change<String, Endpoint>>) {
-        struct WatcherWrapper(Option<Watcher>);
-
-        impl Drop for WatcherWrapper {
-            fn drop(&mut self) {
-                if let Some(mut watcher) = self.0.take() {
-                    if let Ok(handler) = tokio::runtime::Handle::try_current() {
-                        handler.spawn(async move {
-                            // 取消失败了也无所谓, 反正客户端都挂了
-                            watcher.cancel().await.unwrap_or_default();
-                        });
-                    }
-                }
-            }
-        }
-
-        // 启动 watch 任务失败的话, 直接挂了就行
+    /// 从上次看到的 revision 之后开始 watch, 直到 watch 流结束或者负载均衡那边关闭了
+    async fn watch_from_revision(
+        &mut self,
+        service_name: &str,
+        sender: &Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError> {
+        let options = WatchOptions::new()
+            .with_prefix()
+            .with_start_revision(self.revision + 1);
         let (watcher, mut watch_stream) = self
             .etcd_client
-            .watch(service_name, Some(WatchOptions::new().with_prefix()))
-            .await
-            .unwrap();
+            .watch(Self::key_prefix(service_name), Some(options))
+            .await?;
 
         // 自动取消 watch 任务
         let _watcher_wrapper = WatcherWrapper(Some(watcher));
 
-        while let Ok(Some(watch_response)) = watch_stream.message().await {
+        loop {
+            let watch_response = tokio::select! {
+                // 空闲的时候也要能退出, 不然客户端没了 watch 还一直挂在 etcd 上
+                _ = sender.closed() => break,
+                watch_response = watch_stream.message() => watch_response?,
+            };
+            let Some(watch_response) = watch_response else {
+                break;
+            };
+            if watch_response.canceled() {
+                // 比如 revision 已经被压缩了, 只能重新全量拉取
+                info!(
+                    "etcd watch canceled, compact_revision = {}, reason = {}",
+                    watch_response.compact_revision(),
+                    watch_response.cancel_reason()
+                );
+                break;
+            }
             for event in watch_response.events() {
                 if let Some(key_value) = event.kv() {
-                    self.load_balance(event.event_type(), key_value, service_name, &sender)
-                        .await;
+                    self.revision = self.revision.max(key_value.mod_revision());
+                    self.load_balance(event.event_type(), key_value, service_name, sender)
+                        .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl Discovery for EtcdDiscovery {
+    async fn get_server(
+        &mut self,
+        service_name: &str,
+        sender: Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError> {
+        self.list(service_name, &sender).await
+    }
+
+    async fn watch(
+        &mut self,
+        service_name: &str,
+        sender: Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError> {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let (revision, started) = (self.revision, Instant::now());
+            match self.watch_from_revision(service_name, &sender).await {
+                Ok(()) => info!("etcd watch stream over, reconnecting"),
+                Err(err) => error!("etcd watch error: {}, reconnecting", err),
+            }
+            // watch 收到过事件或者撑过了一段时间才重置退避, 一连上就失败的不能变成不停地全量拉取
+            if self.revision > revision || started.elapsed() >= MAX_BACKOFF {
+                backoff = MIN_BACKOFF;
+            }
+            // 断线期间可能错过了事件, 重新全量拉取一次再继续 watch
+            loop {
+                tokio::select! {
+                    _ = sender.closed() => {
+                        info!("etcd watch server exit");
+                        return Ok(());
+                    }
+                    _ = tokio::time::sleep(backoff) => {}
+                }
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                match self.list(service_name, &sender).await {
+                    Ok(()) => break,
+                    Err(err) => error!("etcd get server error: {}, retry after {:?}", err, backoff),
                 }
             }
         }
-        info!("etcd watch server exit");
     }
 }
 
 impl EtcdDiscovery {
     pub fn new(etcd_client: Client) -> Self {
-        Self { etcd_client }
+        Self {
+            etcd_client,
+            endpoints: PushedEndpoints::default(),
+            revision: 0,
+        }
     }
 }
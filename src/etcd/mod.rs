@@ -1,9 +1,17 @@
 pub mod discovery;
 pub mod register;
+pub mod route;
 
-use etcd_client::{Client, ConnectOptions};
+use anyhow::{bail, Context};
+use etcd_client::{Certificate, Client, ConnectOptions, Identity, TlsOptions};
 use std::time::Duration;
 
+// 重新注册、重新 watch 的退避时间
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+const DEFAULT_CONNECT_TIMEOUT: u64 = 1000;
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct EtcdConf {
     #[serde(rename = "Hosts")]
@@ -12,6 +20,31 @@ pub struct EtcdConf {
     pub user: Option<String>,
     #[serde(rename = "Pass", skip_serializing_if = "Option::is_none")]
     pub pass: Option<String>,
+    // 配置了 CA 证书或者客户端证书就会走 TLS 连接
+    #[serde(rename = "CACertFile", skip_serializing_if = "Option::is_none")]
+    pub ca_cert_file: Option<String>,
+    #[serde(rename = "CertFile", skip_serializing_if = "Option::is_none")]
+    pub cert_file: Option<String>,
+    #[serde(rename = "CertKeyFile", skip_serializing_if = "Option::is_none")]
+    pub cert_key_file: Option<String>,
+    // 校验服务端证书时使用的域名, 不配置就用 Hosts 里面的地址
+    #[serde(rename = "DomainName", skip_serializing_if = "Option::is_none")]
+    pub domain_name: Option<String>,
+    // 连接超时, 毫秒
+    #[serde(rename = "ConnectTimeout", default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    // 请求超时, 毫秒
+    #[serde(rename = "Timeout", skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    // http2 keep-alive 的间隔和超时, 毫秒, 两个都配置了才生效
+    #[serde(rename = "KeepAliveInterval", skip_serializing_if = "Option::is_none")]
+    pub keep_alive_interval: Option<u64>,
+    #[serde(rename = "KeepAliveTimeout", skip_serializing_if = "Option::is_none")]
+    pub keep_alive_timeout: Option<u64>,
+}
+
+fn default_connect_timeout() -> u64 {
+    DEFAULT_CONNECT_TIMEOUT
 }
 
 impl AsRef<EtcdConf> for EtcdConf {
@@ -22,9 +55,53 @@ impl AsRef<EtcdConf> for EtcdConf {
 
 impl EtcdConf {
     pub async fn new_etcd_client(&self) -> anyhow::Result<Client> {
-        let conn_option = ConnectOptions::new().with_connect_timeout(Duration::from_secs(1));
+        let mut conn_option =
+            ConnectOptions::new().with_connect_timeout(Duration::from_millis(self.connect_timeout));
+        if let Some(timeout) = self.timeout {
+            conn_option = conn_option.with_timeout(Duration::from_millis(timeout));
+        }
+        if let (Some(interval), Some(timeout)) = (self.keep_alive_interval, self.keep_alive_timeout)
+        {
+            conn_option = conn_option.with_keep_alive(
+                Duration::from_millis(interval),
+                Duration::from_millis(timeout),
+            );
+        }
+        if let (Some(user), Some(pass)) = (&self.user, &self.pass) {
+            conn_option = conn_option.with_user(user, pass);
+        }
+        if let Some(tls_options) = self.tls_options()? {
+            conn_option = conn_option.with_tls(tls_options);
+        }
         let endpoint: Vec<&str> = self.hosts.split(",").collect();
         let etcd_client = Client::connect(endpoint, Some(conn_option)).await?;
         Ok(etcd_client)
     }
+
+    fn tls_options(&self) -> anyhow::Result<Option<TlsOptions>> {
+        if self.ca_cert_file.is_none() && self.cert_file.is_none() && self.cert_key_file.is_none() {
+            return Ok(None);
+        }
+        let mut tls_options = TlsOptions::new();
+        if let Some(ca_cert_file) = &self.ca_cert_file {
+            let ca_cert = std::fs::read(ca_cert_file)
+                .with_context(|| format!("read CACertFile {} failed", ca_cert_file))?;
+            tls_options = tls_options.ca_certificate(Certificate::from_pem(ca_cert));
+        }
+        match (&self.cert_file, &self.cert_key_file) {
+            (Some(cert_file), Some(cert_key_file)) => {
+                let cert = std::fs::read(cert_file)
+                    .with_context(|| format!("read CertFile {} failed", cert_file))?;
+                let key = std::fs::read(cert_key_file)
+                    .with_context(|| format!("read CertKeyFile {} failed", cert_key_file))?;
+                tls_options = tls_options.identity(Identity::from_pem(cert, key));
+            }
+            (None, None) => {}
+            _ => bail!("CertFile and CertKeyFile must be configured together"),
+        }
+        if let Some(domain_name) = &self.domain_name {
+            tls_options = tls_options.domain_name(domain_name);
+        }
+        Ok(Some(tls_options))
+    }
 }
@@ -1,11 +1,15 @@
 use crate::common::ServiceInstance;
 use crate::error::ZrpcError;
-use crate::etcd::EtcdConf;
-use crate::register::Register;
-use anyhow::anyhow;
+use crate::etcd::{EtcdConf, MAX_BACKOFF, MIN_BACKOFF};
+use crate::register::{Deregister, Register};
+use crate::shedder::ServerShedderConf;
+use crate::sre_breaker::ServerSreBreakerConf;
 use etcd_client::{Client, PutOptions};
-use std::time::Duration;
-use tool::log::trace_log::info;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tool::log::trace_log::{error, info};
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct ServerConf {
@@ -17,6 +21,32 @@ pub struct ServerConf {
     endpoint: String,
     #[serde(rename = "Etcd")]
     etcd_conf: EtcdConf,
+    #[serde(rename = "Weight", skip_serializing_if = "Option::is_none")]
+    weight: Option<u32>,
+    #[serde(rename = "Version", skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(rename = "Zone", skip_serializing_if = "Option::is_none")]
+    zone: Option<String>,
+    #[serde(
+        rename = "Metadata",
+        default,
+        skip_serializing_if = "HashMap::is_empty"
+    )]
+    metadata: HashMap<String, String>,
+    // handler 的默认超时, 毫秒, 不配置就不限制
+    #[serde(rename = "Timeout", skip_serializing_if = "Option::is_none")]
+    timeout: Option<u64>,
+    // 按方法覆盖默认的超时
+    #[serde(
+        rename = "MethodTimeouts",
+        default,
+        skip_serializing_if = "HashMap::is_empty"
+    )]
+    method_timeouts: HashMap<String, u64>,
+    #[serde(rename = "SreBreaker", skip_serializing_if = "Option::is_none")]
+    sre_breaker_conf: Option<ServerSreBreakerConf>,
+    #[serde(rename = "Shedder", skip_serializing_if = "Option::is_none")]
+    shedder_conf: Option<ServerShedderConf>,
 }
 
 impl ServerConf {
@@ -35,25 +65,59 @@ impl ServerConf {
     pub fn get_etcd_conf(&self) -> &EtcdConf {
         &self.etcd_conf
     }
+
+    pub fn get_weight(&self) -> Option<u32> {
+        self.weight
+    }
+
+    pub fn get_version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    pub fn get_zone(&self) -> Option<&str> {
+        self.zone.as_deref()
+    }
+
+    pub fn get_metadata(&self) -> &HashMap<String, String> {
+        &self.metadata
+    }
+
+    pub fn get_timeout(&self) -> Option<u64> {
+        self.timeout
+    }
+
+    pub fn get_method_timeouts(&self) -> &HashMap<String, u64> {
+        &self.method_timeouts
+    }
+
+    pub fn get_sre_breaker_conf(&self) -> Option<&ServerSreBreakerConf> {
+        self.sre_breaker_conf.as_ref()
+    }
+
+    pub fn get_shedder_conf(&self) -> Option<&ServerShedderConf> {
+        self.shedder_conf.as_ref()
+    }
 }
 
 pub struct EtcdRegister {
     etcd_client: Client,
     ttl: i64, // ttl 秒
-    interval: Duration,
+    lease_ttl: Duration,
 }
 
 impl EtcdRegister {
-    pub async fn new(etcd_conf: impl AsRef<EtcdConf>, ttl: i64) -> Self {
-        Self {
-            etcd_client: etcd_conf
-                .as_ref()
-                .new_etcd_client()
-                .await
-                .expect("new etcd client failed"),
-            ttl,
-            interval: Duration::from_millis((1000 * ttl / 2) as u64),
+    pub async fn new(etcd_conf: impl AsRef<EtcdConf>, ttl: i64) -> Result<Self, ZrpcError> {
+        // 0 的话续约间隔是 0, tokio 的 interval 会 panic
+        if ttl <= 0 {
+            return Err(ZrpcError::RegisterError(format!(
+                "ttl must be positive, got {ttl}"
+            )));
         }
+        Ok(Self {
+            etcd_client: etcd_conf.as_ref().new_etcd_client().await?,
+            ttl,
+            lease_ttl: Duration::from_secs(ttl as u64),
+        })
     }
 
     async fn register_with_kv(
@@ -67,53 +131,152 @@ impl EtcdRegister {
             .await?;
         Ok(())
     }
-}
 
-#[tonic::async_trait]
-impl Register for EtcdRegister {
-    async fn register(mut self, server_instance: &ServiceInstance) -> Result<(), ZrpcError> {
+    /// 申请一个新的租约并把 key 绑定上去, 返回租约 id
+    async fn register_with_lease(&mut self, key: &str, value: &[u8]) -> Result<i64, ZrpcError> {
         let lease_response = self.etcd_client.lease_grant(self.ttl, None).await?;
         let lease_id = lease_response.id();
-        self.register_with_kv(
-            &*server_instance.key,
-            serde_json::to_vec(server_instance)?,
-            lease_id,
-        )
-        .await?;
-        let (mut lease_keeper, mut lease_keep_stream) =
-            self.etcd_client.lease_keep_alive(lease_id).await?;
-        use tokio::sync::mpsc;
-        let (cancel_tx, mut cancel_rx) = mpsc::unbounded_channel();
-        tokio::spawn(async move {
-            loop {
-                tokio::select! {
-                    _ = cancel_rx.recv() => {
-                        info!("cancel keep_alive");
-                        return;
+        self.register_with_kv(key, value, lease_id).await?;
+        Ok(lease_id)
+    }
+
+    /// 一直续约, 直到被取消(返回 true)或者租约失效(返回 false)
+    async fn keep_alive(&mut self, lease_id: i64, cancel_rx: &mut oneshot::Receiver<()>) -> bool {
+        let keep_alive = tokio::select! {
+            _ = &mut *cancel_rx => return true,
+            keep_alive = self.etcd_client.lease_keep_alive(lease_id) => keep_alive,
+        };
+        let (mut lease_keeper, mut lease_keep_stream) = match keep_alive {
+            Ok(keep_alive) => keep_alive,
+            Err(err) => {
+                error!("lease_keep_alive error: {}", err);
+                return false;
+            }
+        };
+        // 半个 ttl 续约一次
+        let mut interval = tokio::time::interval(self.lease_ttl / 2);
+        loop {
+            tokio::select! {
+                _ = &mut *cancel_rx => {
+                    info!("cancel keep_alive");
+                    return true;
+                }
+                _ = interval.tick() => {
+                    if let Err(err) = lease_keeper.keep_alive().await {
+                        error!("lease_keeper keep_alive error: {}", err);
+                        return false;
                     }
-                    _ = tokio::time::sleep(self.interval) => {
-                        lease_keeper.keep_alive().await.expect("lease_keeper keep_alive error");
+                }
+                res = lease_keep_stream.message() => {
+                    match res {
+                        Ok(Some(resp)) if resp.ttl() > 0 => continue,
+                        Ok(Some(_)) => info!("租约已经过期, 需要重新注册"),
+                        Ok(None) => info!("keep_alive stream over, 需要重新注册"),
+                        Err(err) => error!("keep_alive stream error: {}, 需要重新注册", err),
                     }
+                    return false;
                 }
             }
-        });
-        while let Ok(res) = lease_keep_stream.message().await {
-            if let Some(resp) = res {
-                if resp.ttl() <= 0 {
-                    info!("租约已经过期, 需要重新注册");
-                    cancel_tx
-                        .send(())
-                        .map_err(|e| anyhow!("cancel_tx send error: {e}"))?;
-                    break;
-                } // 说明已经过期, 可能需要重新注册
-                continue;
+        }
+    }
+
+    /// 守护注册信息: 租约失效后撤销旧租约, 重新申请租约并注册. 每次失效都按指数退避,
+    /// 续约撑过一个 ttl 才重置退避. 被取消时返回当前的租约 id, 用于注销, 已经撤销了就是 None.
+    /// 每一步访问 etcd 的时候都要能被取消, 否则 etcd 连不上时注销会一直卡住
+    async fn supervise(
+        mut self,
+        key: String,
+        value: Vec<u8>,
+        mut lease_id: i64,
+        mut cancel_rx: oneshot::Receiver<()>,
+    ) -> Option<i64> {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let started = Instant::now();
+            if self.keep_alive(lease_id, &mut cancel_rx).await {
+                return Some(lease_id);
+            }
+            if started.elapsed() >= self.lease_ttl {
+                backoff = MIN_BACKOFF;
             }
-            info!("keep_alive stream over");
-            cancel_tx
-                .send(())
-                .map_err(|e| anyhow!("cancel_tx send error: {e}"))?;
-            break;
+            // 续约的流断了的话旧租约可能还活着, 先撤销掉, 不然会和新的注册同时存在到过期.
+            // 撤销的时候被取消了就交给注销再撤销一次
+            let revoke_result = tokio::select! {
+                _ = &mut cancel_rx => return Some(lease_id),
+                revoke_result = self.etcd_client.lease_revoke(lease_id) => revoke_result,
+            };
+            if let Err(err) = revoke_result {
+                info!("revoke lease {} failed: {}", lease_id, err);
+            }
+            lease_id = loop {
+                tokio::select! {
+                    _ = &mut cancel_rx => return None,
+                    _ = tokio::time::sleep(backoff) => {}
+                }
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                // 被取消时新租约可能已经申请下来了, 注销会删掉 key, 租约自己过期
+                let register_result = tokio::select! {
+                    _ = &mut cancel_rx => return None,
+                    register_result = self.register_with_lease(&key, &value) => register_result,
+                };
+                match register_result {
+                    Ok(lease_id) => {
+                        info!("re-register success, key = {}", key);
+                        break lease_id;
+                    }
+                    Err(err) => error!("re-register failed: {}, retry after {:?}", err, backoff),
+                }
+            };
         }
+    }
+}
+
+pub struct EtcdRegisterHandle {
+    etcd_client: Client,
+    key: String,
+    cancel_tx: oneshot::Sender<()>,
+    supervisor: JoinHandle<Option<i64>>,
+}
+
+#[tonic::async_trait]
+impl Deregister for EtcdRegisterHandle {
+    async fn deregister(mut self) -> Result<(), ZrpcError> {
+        // 先停掉续约, 等守护任务退出后拿到最新的租约 id
+        let _ = self.cancel_tx.send(());
+        let lease_id = self.supervisor.await.map_err(|e| {
+            ZrpcError::RegisterError(format!("register supervisor exit abnormally: {e}"))
+        })?;
+        // 撤销租约会一并删除绑定的 key, 这里再显式删一次, 防止租约已经不存在了
+        let revoke_result = match lease_id {
+            Some(lease_id) => self.etcd_client.lease_revoke(lease_id).await.map(drop),
+            None => Ok(()),
+        };
+        self.etcd_client.delete(self.key.as_str(), None).await?;
+        revoke_result?;
+        info!("deregister success, key = {}", self.key);
         Ok(())
     }
 }
+
+#[tonic::async_trait]
+impl Register for EtcdRegister {
+    type Handle = EtcdRegisterHandle;
+
+    async fn register(
+        mut self,
+        server_instance: &ServiceInstance,
+    ) -> Result<Self::Handle, ZrpcError> {
+        let key = server_instance.key.clone();
+        let value = serde_json::to_vec(server_instance)?;
+        let lease_id = self.register_with_lease(&key, &value).await?;
+        let etcd_client = self.etcd_client.clone();
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        let supervisor = tokio::spawn(self.supervise(key.clone(), value, lease_id, cancel_rx));
+        Ok(EtcdRegisterHandle {
+            etcd_client,
+            key,
+            cancel_tx,
+            supervisor,
+        })
+    }
+}
@@ -0,0 +1,151 @@
+use crate::balance::{RouteRule, RouteTable};
+use crate::error::ZrpcError;
+use crate::etcd::{MAX_BACKOFF, MIN_BACKOFF};
+use etcd_client::{Client, EventType, WatchOptions, WatchStream};
+use tokio::sync::oneshot;
+use tool::log::trace_log::{error, info};
+
+/// 从 etcd 的一个 key 加载路由规则, 并 watch 这个 key 热更新 RouteTable.
+/// key 不存在或者被删除时恢复成创建时 RouteTable 里的规则, 内容解析失败时保留当前的规则
+pub struct EtcdRouteWatcher {
+    etcd_client: Client,
+    key: String,
+    route_table: RouteTable,
+    defaults: Vec<RouteRule>,
+    revision: i64,
+}
+
+impl EtcdRouteWatcher {
+    pub fn new(etcd_client: Client, key: impl Into<String>, route_table: RouteTable) -> Self {
+        let defaults = route_table.get_rules().as_ref().clone();
+        Self {
+            etcd_client,
+            key: key.into(),
+            route_table,
+            defaults,
+            revision: 0,
+        }
+    }
+
+    fn update(&self, value: Option<&[u8]>) {
+        let Some(value) = value else {
+            info!("route rules {} not found, use defaults", self.key);
+            self.route_table.update(self.defaults.clone());
+            return;
+        };
+        match serde_yaml::from_slice::<Vec<RouteRule>>(value) {
+            Ok(rules) => {
+                info!("route rules {} updated: {:?}", self.key, rules);
+                self.route_table.update(rules);
+            }
+            Err(err) => error!(
+                "invalid route rules {}: {}, {}",
+                self.key,
+                err,
+                String::from_utf8_lossy(value)
+            ),
+        }
+    }
+
+    /// 拉取一次当前的规则
+    pub async fn load(&mut self) -> Result<(), ZrpcError> {
+        let response = self.etcd_client.get(self.key.as_str(), None).await?;
+        self.update(response.kvs().first().map(|kv| kv.value()));
+        if let Some(header) = response.header() {
+            self.revision = header.revision();
+        }
+        Ok(())
+    }
+
+    /// watch 到流结束或者出错, 被取消时返回 true. 返回前都会取消 etcd 那边的 watch
+    async fn watch_from_revision(
+        &mut self,
+        closed: &mut oneshot::Receiver<()>,
+    ) -> Result<bool, ZrpcError> {
+        let options = WatchOptions::new().with_start_revision(self.revision + 1);
+        let (mut watcher, mut watch_stream) = self
+            .etcd_client
+            .watch(self.key.as_str(), Some(options))
+            .await?;
+        let result = self.handle_watch_stream(&mut watch_stream, closed).await;
+        watcher.cancel().await.unwrap_or_default();
+        result
+    }
+
+    async fn handle_watch_stream(
+        &mut self,
+        watch_stream: &mut WatchStream,
+        closed: &mut oneshot::Receiver<()>,
+    ) -> Result<bool, ZrpcError> {
+        loop {
+            let watch_response = tokio::select! {
+                _ = &mut *closed => return Ok(true),
+                watch_response = watch_stream.message() => watch_response?,
+            };
+            let Some(watch_response) = watch_response else {
+                return Ok(false);
+            };
+            if watch_response.canceled() {
+                info!(
+                    "etcd watch route canceled, reason = {}",
+                    watch_response.cancel_reason()
+                );
+                return Ok(false);
+            }
+            for event in watch_response.events() {
+                if let Some(key_value) = event.kv() {
+                    self.revision = self.revision.max(key_value.mod_revision());
+                    match event.event_type() {
+                        EventType::Put => self.update(Some(key_value.value())),
+                        EventType::Delete => self.update(None),
+                    }
+                }
+            }
+        }
+    }
+
+    /// 一直 watch, 断线后退避重连, 重连前重新拉取一次. closed 收到消息或者发送端被丢弃时退出
+    pub async fn watch(mut self, mut closed: oneshot::Receiver<()>) {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            match self.watch_from_revision(&mut closed).await {
+                Ok(true) => {
+                    info!("etcd watch route {} exit", self.key);
+                    return;
+                }
+                Ok(false) => info!("etcd watch route stream over, reconnecting"),
+                Err(err) => error!("etcd watch route error: {}, reconnecting", err),
+            }
+            loop {
+                tokio::select! {
+                    _ = &mut closed => {
+                        info!("etcd watch route {} exit", self.key);
+                        return;
+                    }
+                    _ = tokio::time::sleep(backoff) => {}
+                }
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                match self.load().await {
+                    Ok(()) => {
+                        backoff = MIN_BACKOFF;
+                        break;
+                    }
+                    Err(err) => error!("etcd get route error: {}, retry after {:?}", err, backoff),
+                }
+            }
+        }
+    }
+}
+
+/// 先同步加载一次规则, 再在后台 watch. 返回的 RouteTable 和它的克隆全部被丢弃之后 watch 退出
+pub async fn watch_route_rules(
+    etcd_client: Client,
+    key: impl Into<String>,
+    route_table: RouteTable,
+) -> Result<RouteTable, ZrpcError> {
+    let mut watcher = EtcdRouteWatcher::new(etcd_client, key, route_table.clone());
+    watcher.load().await?;
+    let (closed_tx, closed_rx) = oneshot::channel();
+    tokio::spawn(watcher.watch(closed_rx));
+    Ok(route_table.with_closed(closed_tx))
+}
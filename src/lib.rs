@@ -1,13 +1,19 @@
+mod balance;
 mod client;
 mod common;
 mod discovery;
 mod error;
 pub mod etcd;
+pub mod memory;
 mod middleware;
 mod register;
 mod server;
 
+pub use balance::*;
 pub use client::*;
 pub use common::*;
+pub use discovery::*;
+pub use error::*;
 pub use middleware::*;
+pub use register::*;
 pub use server::*;
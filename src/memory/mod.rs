@@ -0,0 +1,306 @@
+use crate::common::ServiceInstance;
+use crate::discovery::{Discovery, PushedEndpoints};
+use crate::error::ZrpcError;
+use crate::register::{Deregister, Register};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{broadcast, oneshot};
+use tool::log::trace_log::info;
+use tower::discover::Change;
+
+const EVENT_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone)]
+enum RegistryEvent {
+    Put(ServiceInstance),
+    Delete(ServiceInstance),
+}
+
+impl RegistryEvent {
+    fn name(&self) -> &str {
+        match self {
+            RegistryEvent::Put(instance) | RegistryEvent::Delete(instance) => &instance.name,
+        }
+    }
+}
+
+struct Registry {
+    // key -> (实例, 租约 id)
+    instances: Mutex<HashMap<String, (ServiceInstance, u64)>>,
+    events: broadcast::Sender<RegistryEvent>,
+    next_lease: AtomicU64,
+}
+
+impl Registry {
+    fn put(&self, instance: ServiceInstance) -> u64 {
+        let lease = self.next_lease.fetch_add(1, Ordering::Relaxed);
+        let mut instances = self.instances.lock().unwrap();
+        instances.insert(instance.key.clone(), (instance.clone(), lease));
+        // 持有锁的时候广播, 保证和 subscribe 拿到的快照顺序一致. 没有订阅者时发送失败, 无所谓
+        let _ = self.events.send(RegistryEvent::Put(instance));
+        lease
+    }
+
+    /// lease 为 None 时直接删除, 否则只有租约还是同一个的时候才删除(没有被重新注册过)
+    fn remove(&self, key: &str, lease: Option<u64>) {
+        let mut instances = self.instances.lock().unwrap();
+        if let Some((_, current)) = instances.get(key) {
+            if lease.is_some_and(|lease| lease != *current) {
+                return;
+            }
+            if let Some((instance, _)) = instances.remove(key) {
+                let _ = self.events.send(RegistryEvent::Delete(instance));
+            }
+        }
+    }
+
+    /// 订阅变更, 同时返回订阅时刻该服务的全部实例
+    fn subscribe(
+        &self,
+        service_name: &str,
+    ) -> (broadcast::Receiver<RegistryEvent>, Vec<ServiceInstance>) {
+        let instances = self.instances.lock().unwrap();
+        let receiver = self.events.subscribe();
+        let snapshot = instances
+            .values()
+            .filter(|(instance, _)| instance.name == service_name)
+            .map(|(instance, _)| instance.clone())
+            .collect();
+        (receiver, snapshot)
+    }
+}
+
+/// 内存版的注册中心, 同时实现了 Register 和 Discovery, 克隆出来的共享同一份数据.
+/// 用于测试或者不部署 etcd 的单进程场景
+pub struct MemoryRegistry {
+    registry: Arc<Registry>,
+    ttl: Duration,
+    receiver: Option<broadcast::Receiver<RegistryEvent>>,
+    endpoints: PushedEndpoints,
+}
+
+impl Clone for MemoryRegistry {
+    fn clone(&self) -> Self {
+        Self {
+            registry: self.registry.clone(),
+            ttl: self.ttl,
+            receiver: None,
+            endpoints: PushedEndpoints::default(),
+        }
+    }
+}
+
+impl MemoryRegistry {
+    /// ttl: 注册句柄被丢弃(没有注销)后, 实例还会保留多久, 相当于 etcd 的租约
+    pub fn new(ttl: Duration) -> Self {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        Self {
+            registry: Arc::new(Registry {
+                instances: Mutex::new(HashMap::new()),
+                events,
+                next_lease: AtomicU64::new(0),
+            }),
+            ttl,
+            receiver: None,
+            endpoints: PushedEndpoints::default(),
+        }
+    }
+}
+
+pub struct MemoryRegisterHandle {
+    registry: Arc<Registry>,
+    key: String,
+    cancel_tx: oneshot::Sender<()>,
+}
+
+#[tonic::async_trait]
+impl Deregister for MemoryRegisterHandle {
+    async fn deregister(self) -> Result<(), ZrpcError> {
+        let _ = self.cancel_tx.send(());
+        self.registry.remove(&self.key, None);
+        info!("deregister success, key = {}", self.key);
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl Register for MemoryRegistry {
+    type Handle = MemoryRegisterHandle;
+
+    async fn register(
+        mut self,
+        server_instance: &ServiceInstance,
+    ) -> Result<Self::Handle, ZrpcError> {
+        let lease = self.registry.put(server_instance.clone());
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        let registry = self.registry.clone();
+        let key = server_instance.key.clone();
+        let ttl = self.ttl;
+        tokio::spawn(async move {
+            // 句柄被丢弃而没有注销, 相当于进程挂了不再续约, ttl 之后过期
+            if cancel_rx.await.is_err() {
+                tokio::time::sleep(ttl).await;
+                info!("租约已经过期, key = {}", key);
+                registry.remove(&key, Some(lease));
+            }
+        });
+        Ok(MemoryRegisterHandle {
+            registry: self.registry,
+            key: server_instance.key.clone(),
+            cancel_tx,
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl Discovery for MemoryRegistry {
+    async fn get_server(
+        &mut self,
+        service_name: &str,
+        sender: Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError> {
+        let (receiver, instances) = self.registry.subscribe(service_name);
+        self.receiver = Some(receiver);
+        self.endpoints.sync(instances, &sender).await
+    }
+
+    async fn watch(
+        &mut self,
+        service_name: &str,
+        sender: Sender<Change<String, ServiceInstance>>,
+    ) -> Result<(), ZrpcError> {
+        let mut receiver = match self.receiver.take() {
+            Some(receiver) => receiver,
+            None => {
+                let (receiver, instances) = self.registry.subscribe(service_name);
+                self.endpoints.sync(instances, &sender).await?;
+                receiver
+            }
+        };
+        loop {
+            let event = tokio::select! {
+                _ = sender.closed() => {
+                    info!("memory watch server exit");
+                    return Ok(());
+                }
+                event = receiver.recv() => event,
+            };
+            match event {
+                Ok(event) if event.name() != service_name => {}
+                Ok(RegistryEvent::Put(instance)) => {
+                    self.endpoints.insert(instance, &sender).await?
+                }
+                Ok(RegistryEvent::Delete(instance)) => {
+                    self.endpoints.remove(&instance.key, &sender).await?
+                }
+                Err(RecvError::Lagged(_)) => {
+                    // 落后太多丢了事件, 重新订阅并全量对比一次
+                    let (new_receiver, instances) = self.registry.subscribe(service_name);
+                    receiver = new_receiver;
+                    self.endpoints.sync(instances, &sender).await?;
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::{self, Receiver};
+    use tokio::time::timeout;
+
+    async fn next_change(
+        receiver: &mut Receiver<Change<String, ServiceInstance>>,
+    ) -> Change<String, ServiceInstance> {
+        timeout(Duration::from_secs(1), receiver.recv())
+            .await
+            .expect("no change")
+            .expect("channel closed")
+    }
+
+    #[tokio::test]
+    async fn register_and_discover() {
+        let registry = MemoryRegistry::new(Duration::from_secs(10));
+        let first = ServiceInstance::new("Dev", "user.rpc", "127.0.0.1:1".to_owned());
+        let _first = registry.clone().register(&first).await.unwrap();
+
+        let (sender, mut receiver) = mpsc::channel(16);
+        let mut discovery = registry.clone();
+        discovery
+            .get_server("Dev/user.rpc", sender.clone())
+            .await
+            .unwrap();
+        assert!(
+            matches!(next_change(&mut receiver).await, Change::Insert(key, _) if key == first.key)
+        );
+        tokio::spawn(async move { discovery.watch("Dev/user.rpc", sender).await });
+
+        // 别的服务的变更不推送
+        let other = ServiceInstance::new("Dev", "order.rpc", "127.0.0.1:2".to_owned());
+        let _other = registry.clone().register(&other).await.unwrap();
+        let second = ServiceInstance::new("Dev", "user.rpc", "127.0.0.1:3".to_owned());
+        let second_handle = registry.clone().register(&second).await.unwrap();
+        assert!(
+            matches!(next_change(&mut receiver).await, Change::Insert(key, _) if key == second.key)
+        );
+
+        second_handle.deregister().await.unwrap();
+        assert!(
+            matches!(next_change(&mut receiver).await, Change::Remove(key) if key == second.key)
+        );
+    }
+
+    #[tokio::test]
+    async fn expire_after_handle_dropped() {
+        let registry = MemoryRegistry::new(Duration::from_millis(100));
+        let instance = ServiceInstance::new("Dev", "user.rpc", "127.0.0.1:1".to_owned());
+        let handle = registry.clone().register(&instance).await.unwrap();
+
+        let (sender, mut receiver) = mpsc::channel(16);
+        let mut discovery = registry.clone();
+        tokio::spawn(async move { discovery.watch("Dev/user.rpc", sender).await });
+        assert!(matches!(
+            next_change(&mut receiver).await,
+            Change::Insert(..)
+        ));
+
+        drop(handle);
+        // ttl 之内还在
+        assert!(timeout(Duration::from_millis(50), receiver.recv())
+            .await
+            .is_err());
+        assert!(
+            matches!(next_change(&mut receiver).await, Change::Remove(key) if key == instance.key)
+        );
+    }
+
+    #[tokio::test]
+    async fn resync_after_lagged() {
+        let registry = MemoryRegistry::new(Duration::from_secs(10));
+        let (sender, mut receiver) = mpsc::channel(16);
+        let mut discovery = registry.clone();
+        discovery
+            .get_server("Dev/user.rpc", sender.clone())
+            .await
+            .unwrap();
+
+        // 订阅之后、开始 watch 之前塞满事件, 这个实例的 Put 会被挤掉
+        let instance = ServiceInstance::new("Dev", "user.rpc", "127.0.0.1:1".to_owned());
+        let _handle = registry.clone().register(&instance).await.unwrap();
+        for port in 0..EVENT_CAPACITY {
+            let other = ServiceInstance::new("Dev", "order.rpc", format!("127.0.0.1:{port}"));
+            registry.registry.put(other);
+        }
+
+        tokio::spawn(async move { discovery.watch("Dev/user.rpc", sender).await });
+        assert!(
+            matches!(next_change(&mut receiver).await, Change::Insert(key, _) if key == instance.key)
+        );
+    }
+}
@@ -0,0 +1,221 @@
+use pin_project_lite::pin_project;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::task::futures::TaskLocalFuture;
+use tonic::body::BoxBody;
+use tonic::codegen::{http, Service};
+use tonic::Status;
+use tower::BoxError;
+
+const GRPC_TIMEOUT_HEADER: &str = "grpc-timeout";
+
+tokio::task_local! {
+    static DEADLINE: Instant;
+}
+
+/// 当前请求的截止时间, 在 ServerDeadline 处理的请求里面才有
+pub fn get_deadline() -> Option<Instant> {
+    DEADLINE.try_with(|deadline| *deadline).ok()
+}
+
+/// 在 future 里面设置截止时间. handler 里 spawn 出去的任务拿不到 task-local, 要自己包一层
+pub fn with_deadline<F: Future>(deadline: Instant, f: F) -> TaskLocalFuture<Instant, F> {
+    DEADLINE.scope(deadline, f)
+}
+
+/// 解析 grpc-timeout, 格式是最多 8 位数字加一个单位
+fn parse_timeout(value: &str) -> Option<Duration> {
+    if value.len() < 2 || value.len() > 9 || !value.is_ascii() {
+        return None;
+    }
+    let (digits, unit) = value.split_at(value.len() - 1);
+    let value: u64 = digits.parse().ok()?;
+    let timeout = match unit {
+        "H" => Duration::from_secs(value * 60 * 60),
+        "M" => Duration::from_secs(value * 60),
+        "S" => Duration::from_secs(value),
+        "m" => Duration::from_millis(value),
+        "u" => Duration::from_micros(value),
+        "n" => Duration::from_nanos(value),
+        _ => return None,
+    };
+    Some(timeout)
+}
+
+/// 选能放进 8 位数字的最小单位
+fn encode_timeout(timeout: Duration) -> String {
+    const UNITS: [(u128, &str); 6] = [
+        (1, "n"),
+        (1_000, "u"),
+        (1_000_000, "m"),
+        (1_000_000_000, "S"),
+        (60_000_000_000, "M"),
+        (3_600_000_000_000, "H"),
+    ];
+    let nanos = timeout.as_nanos();
+    for (scale, unit) in UNITS {
+        // 向上取整, 不要把剩下的时间变成 0
+        let value = nanos.div_ceil(scale);
+        if value < 100_000_000 {
+            return format!("{value}{unit}");
+        }
+    }
+    "99999999H".to_owned()
+}
+
+/// 从请求头里拿 grpc-timeout
+pub(crate) fn get_timeout(headers: &http::HeaderMap) -> Option<Duration> {
+    parse_timeout(headers.get(GRPC_TIMEOUT_HEADER)?.to_str().ok()?)
+}
+
+/// 发出去的请求带上剩下的时间. 请求自己设置的 grpc-timeout 更短的话用请求的.
+/// 已经超时的直接返回 DeadlineExceeded, 不再发出去
+pub(crate) fn propagate_deadline<B>(request: &mut http::Request<B>) -> Result<(), BoxError> {
+    let Some(deadline) = get_deadline() else {
+        return Ok(());
+    };
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(Status::deadline_exceeded("deadline exceeded before sending request").into());
+    }
+    if get_timeout(request.headers()).is_some_and(|timeout| timeout <= remaining) {
+        return Ok(());
+    }
+    let value = encode_timeout(remaining)
+        .parse()
+        .expect("grpc-timeout is a valid header value");
+    request.headers_mut().insert(GRPC_TIMEOUT_HEADER, value);
+    Ok(())
+}
+
+/// 把请求的 grpc-timeout 转成截止时间放到 task-local 里, handler 里用 zrpc 的 Client 调用别的服务时,
+/// 自动带上剩下的时间. 到达时就已经超时的请求直接返回 DeadlineExceeded, 不会调用到 handler
+#[derive(Clone)]
+pub struct ServerDeadline;
+
+impl<S> tower::Layer<S> for ServerDeadline {
+    type Service = ServerDeadlineInner<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ServerDeadlineInner { inner: service }
+    }
+}
+
+#[derive(Clone)]
+pub struct ServerDeadlineInner<S> {
+    inner: S,
+}
+
+pin_project! {
+    #[project = ResponseFutureProj]
+    pub enum ResponseFuture<F> {
+        Scoped {
+            #[pin]
+            inner: TaskLocalFuture<Instant, F>,
+        },
+        // 请求没有带 grpc-timeout
+        Unscoped {
+            #[pin]
+            inner: F,
+        },
+        Expired {
+            status: Option<Status>,
+        },
+    }
+}
+
+impl<S, ReqBody> Service<http::Request<ReqBody>> for ServerDeadlineInner<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<BoxBody>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<ReqBody>) -> Self::Future {
+        let Some(timeout) = get_timeout(req.headers()) else {
+            return ResponseFuture::Unscoped {
+                inner: self.inner.call(req),
+            };
+        };
+        if timeout.is_zero() {
+            return ResponseFuture::Expired {
+                status: Some(Status::deadline_exceeded(
+                    "deadline exceeded before handling request",
+                )),
+            };
+        }
+        let deadline = Instant::now() + timeout;
+        ResponseFuture::Scoped {
+            inner: with_deadline(deadline, self.inner.call(req)),
+        }
+    }
+}
+
+impl<F, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<http::Response<BoxBody>, E>>,
+{
+    type Output = Result<http::Response<BoxBody>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project() {
+            ResponseFutureProj::Scoped { inner } => inner.poll(cx),
+            ResponseFutureProj::Unscoped { inner } => inner.poll(cx),
+            ResponseFutureProj::Expired { status } => {
+                let status = status.take().expect("polled after completion");
+                Poll::Ready(Ok(status.into_http()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_timeout("1H"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_timeout("2M"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_timeout("3S"), Some(Duration::from_secs(3)));
+        assert_eq!(parse_timeout("4m"), Some(Duration::from_millis(4)));
+        assert_eq!(parse_timeout("5u"), Some(Duration::from_micros(5)));
+        assert_eq!(
+            parse_timeout("99999999n"),
+            Some(Duration::from_nanos(99999999))
+        );
+        // 没有单位、不认识的单位、超过 8 位数字
+        for value in ["", "1", "m", "1s", "1.5S", "100000000m", "-1m"] {
+            assert_eq!(parse_timeout(value), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!(encode_timeout(Duration::from_nanos(99999999)), "99999999n");
+        assert_eq!(encode_timeout(Duration::from_millis(1500)), "1500000u");
+        assert_eq!(encode_timeout(Duration::from_secs(3600 * 24)), "86400000m");
+        // 向上取整
+        assert_eq!(encode_timeout(Duration::from_nanos(100_000_001)), "100001u");
+        assert_eq!(encode_timeout(Duration::MAX), "99999999H");
+    }
+
+    #[test]
+    fn round_trip() {
+        for nanos in [1, 999, 100_000_001, 1_234_567_890, 86_400_000_000_123] {
+            let timeout = Duration::from_nanos(nanos);
+            let encoded = encode_timeout(timeout);
+            let parsed = parse_timeout(&encoded).unwrap();
+            // 不会变短; 换单位时至少有 6 位数字, 多出来的不到十万分之一
+            assert!(parsed >= timeout, "{encoded}");
+            assert!(parsed - timeout <= timeout / 100_000, "{encoded}");
+        }
+    }
+}
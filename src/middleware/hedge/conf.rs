@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+
+const DEFAULT_PERCENT: f64 = 10.0;
+const DEFAULT_PERCENTILE: f64 = 95.0;
+
+/// 一个方法的对冲策略. 配置了 Delay 就按固定的延迟, 否则按这个方法最近耗时的 Percentile 分位数
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct HedgePolicy {
+    // 第一次请求发出去多久没有响应就发第二次, 毫秒
+    #[serde(rename = "Delay", skip_serializing_if = "Option::is_none")]
+    pub delay: Option<u64>,
+    #[serde(rename = "Percentile")]
+    pub percentile: f64,
+}
+
+impl Default for HedgePolicy {
+    fn default() -> Self {
+        Self {
+            delay: None,
+            percentile: DEFAULT_PERCENTILE,
+        }
+    }
+}
+
+/// ClientHedge 的 yaml 配置, 放在 ClientConf 的 Hedge 下面. 只有 Methods 里的方法会对冲,
+/// 这些方法必须是幂等的
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct HedgeConf {
+    // 对冲请求最多占总请求数的百分比
+    #[serde(rename = "Percent")]
+    pub percent: f64,
+    #[serde(rename = "Methods")]
+    pub methods: HashMap<String, HedgePolicy>,
+}
+
+impl Default for HedgeConf {
+    fn default() -> Self {
+        Self {
+            percent: DEFAULT_PERCENT,
+            methods: HashMap::new(),
+        }
+    }
+}
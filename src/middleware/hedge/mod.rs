@@ -0,0 +1,345 @@
+mod conf;
+
+pub use conf::{HedgeConf, HedgePolicy};
+
+use crate::balance::TriedEndpoints;
+use crate::retry::{buffer_body, clone_request, Buffered};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tonic::body::BoxBody;
+use tonic::codegen::{http, BoxFuture, Bytes, Service};
+use tonic::{Code, Status};
+use tower::{BoxError, ServiceExt};
+
+/// 按分位数算延迟时保留最近多少个耗时
+const LATENCY_SAMPLES: usize = 100;
+/// 耗时样本太少的时候分位数不准, 先不对冲
+const MIN_SAMPLES: usize = 20;
+/// 对冲预算最多攒多少个
+const MAX_TOKENS: f64 = 10.0;
+
+/// 客户端的对冲请求. 配置了的方法第一次请求超过延迟还没有响应时, 再发一次给别的节点,
+/// 用先回来的结果, 另一个请求直接取消. 请求体会缓存下来用于重发, 只能配置幂等的方法,
+/// 流式调用和太大的请求体不缓存, 只发一次
+#[derive(Clone)]
+pub struct ClientHedge {
+    percent: f64,
+    methods: Arc<HashMap<String, HedgePolicy>>,
+}
+
+impl ClientHedge {
+    pub fn new(hedge_conf: &HedgeConf) -> Self {
+        Self {
+            percent: hedge_conf.percent,
+            methods: Arc::new(hedge_conf.methods.clone()),
+        }
+    }
+}
+
+impl Default for ClientHedge {
+    fn default() -> Self {
+        Self::new(&HedgeConf::default())
+    }
+}
+
+impl From<&HedgeConf> for ClientHedge {
+    fn from(value: &HedgeConf) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<S> tower::Layer<S> for ClientHedge {
+    type Service = ClientHedgeInner<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        let methods = self
+            .methods
+            .iter()
+            .map(|(path, policy)| {
+                let method = MethodHedge {
+                    policy: policy.clone(),
+                    latency: Mutex::new(VecDeque::with_capacity(LATENCY_SAMPLES)),
+                };
+                (path.clone(), Arc::new(method))
+            })
+            .collect();
+        ClientHedgeInner {
+            inner: service,
+            methods: Arc::new(methods),
+            budget: Arc::new(HedgeBudget::new(self.percent)),
+        }
+    }
+}
+
+/// 一个方法的策略和最近的耗时
+struct MethodHedge {
+    policy: HedgePolicy,
+    latency: Mutex<VecDeque<Duration>>,
+}
+
+impl MethodHedge {
+    /// 固定的延迟, 或者最近耗时的分位数. 样本不够时返回 None, 不对冲
+    fn delay(&self) -> Option<Duration> {
+        if let Some(delay) = self.policy.delay {
+            return Some(Duration::from_millis(delay));
+        }
+        let mut latency: Vec<_> = self.latency.lock().unwrap().iter().copied().collect();
+        if latency.len() < MIN_SAMPLES {
+            return None;
+        }
+        latency.sort_unstable();
+        let index = (latency.len() as f64 * self.policy.percentile / 100.0) as usize;
+        latency.get(index.min(latency.len() - 1)).copied()
+    }
+
+    /// 只统计成功的耗时, 很快返回的失败会把分位数拉低
+    fn observe<E>(&self, result: &Result<http::Response<BoxBody>, E>, start: Instant) {
+        if is_failure(result) {
+            return;
+        }
+        let elapsed = start.elapsed();
+        let mut latency = self.latency.lock().unwrap();
+        if latency.len() == LATENCY_SAMPLES {
+            latency.pop_front();
+        }
+        latency.push_back(elapsed);
+    }
+}
+
+/// 每个请求加 percent / 100 个令牌, 每个对冲请求用掉 1 个
+struct HedgeBudget {
+    token_ratio: f64,
+    tokens: Mutex<f64>,
+}
+
+impl HedgeBudget {
+    fn new(percent: f64) -> Self {
+        Self {
+            token_ratio: percent / 100.0,
+            tokens: Mutex::new(0.0),
+        }
+    }
+
+    fn deposit(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens + self.token_ratio).min(MAX_TOKENS);
+    }
+
+    fn withdraw(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+#[derive(Clone)]
+pub struct ClientHedgeInner<S> {
+    inner: S,
+    methods: Arc<HashMap<String, Arc<MethodHedge>>>,
+    budget: Arc<HedgeBudget>,
+}
+
+impl<S> Service<http::Request<BoxBody>> for ClientHedgeInner<S>
+where
+    S: Service<http::Request<BoxBody>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: http::Request<BoxBody>) -> Self::Future {
+        let Some(method) = self.methods.get(req.uri().path()).cloned() else {
+            let future = self.inner.call(req);
+            return Box::pin(async move { future.await.map_err(Into::into) });
+        };
+        let inner = self.inner.clone();
+        let budget = self.budget.clone();
+        Box::pin(call_with_hedge(inner, req, method, budget))
+    }
+}
+
+async fn call_with_hedge<S>(
+    inner: S,
+    req: http::Request<BoxBody>,
+    method: Arc<MethodHedge>,
+    budget: Arc<HedgeBudget>,
+) -> Result<http::Response<BoxBody>, BoxError>
+where
+    S: Service<http::Request<BoxBody>, Response = http::Response<BoxBody>> + Clone,
+    S::Error: Into<BoxError>,
+{
+    budget.deposit();
+    let (parts, body) = req.into_parts();
+    let body = match buffer_body(body).await? {
+        Buffered::Complete(body) => body,
+        // 流式调用或者请求体太大, 不对冲, 也不统计耗时
+        Buffered::Streaming(body) => {
+            let req = http::Request::from_parts(parts, body);
+            return inner.oneshot(req).await.map_err(Into::into);
+        }
+    };
+    let tried = TriedEndpoints::default();
+    let start = Instant::now();
+    let mut first = pin!(attempt(&inner, &parts, &body, &tried));
+    if let Some(delay) = method.delay() {
+        tokio::select! {
+            result = &mut first => {
+                method.observe(&result, start);
+                return result;
+            }
+            _ = tokio::time::sleep(delay) => {}
+        }
+        if budget.withdraw() {
+            let hedge_start = Instant::now();
+            let mut second = pin!(attempt(&inner, &parts, &body, &tried));
+            let (result, first_won) = tokio::select! {
+                result = &mut first => (result, true),
+                result = &mut second => (result, false),
+            };
+            // 先回来的是失败就等另一个, 没有用到的那个 drop 掉, 请求就取消了
+            let (result, first_won) = if is_failure(&result) {
+                if first_won {
+                    (second.await, false)
+                } else {
+                    (first.await, true)
+                }
+            } else {
+                (result, first_won)
+            };
+            let start = if first_won { start } else { hedge_start };
+            method.observe(&result, start);
+            return result;
+        }
+    }
+    let result = first.await;
+    method.observe(&result, start);
+    result
+}
+
+/// 一次尝试, 和重试的尝试共用已经发过的节点
+fn attempt<S>(
+    inner: &S,
+    parts: &http::request::Parts,
+    body: &Bytes,
+    tried: &TriedEndpoints,
+) -> impl Future<Output = Result<http::Response<BoxBody>, BoxError>>
+where
+    S: Service<http::Request<BoxBody>, Response = http::Response<BoxBody>> + Clone,
+    S::Error: Into<BoxError>,
+{
+    let mut req = clone_request(parts, body.clone());
+    req.extensions_mut().insert(tried.clone());
+    let inner = inner
+        .clone()
+        .map_err(Into::into as fn(S::Error) -> BoxError);
+    Box::pin(inner.oneshot(req))
+}
+
+/// 拿到了响应头而且不是 trailers-only 的错误才算成功
+fn is_failure<E>(result: &Result<http::Response<BoxBody>, E>) -> bool {
+    match result {
+        Ok(response) => Status::from_header_map(response.headers())
+            .is_some_and(|status| status.code() != Code::Ok),
+        Err(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn method(delay: Option<u64>) -> MethodHedge {
+        MethodHedge {
+            policy: HedgePolicy {
+                delay,
+                ..Default::default()
+            },
+            latency: Mutex::new(VecDeque::with_capacity(LATENCY_SAMPLES)),
+        }
+    }
+
+    #[test]
+    fn budget() {
+        let budget = HedgeBudget::new(25.0);
+        assert!(!budget.withdraw());
+        // 4 个请求攒 1 个令牌
+        for _ in 0..3 {
+            budget.deposit();
+        }
+        assert!(!budget.withdraw());
+        budget.deposit();
+        assert!(budget.withdraw());
+        assert!(!budget.withdraw());
+        // 最多攒 MAX_TOKENS 个
+        for _ in 0..1000 {
+            budget.deposit();
+        }
+        assert_eq!(
+            (0..100).filter(|_| budget.withdraw()).count(),
+            MAX_TOKENS as usize
+        );
+    }
+
+    #[test]
+    fn fixed_delay() {
+        assert_eq!(method(Some(30)).delay(), Some(Duration::from_millis(30)));
+    }
+
+    #[test]
+    fn percentile_delay() {
+        let method = method(None);
+        let mut latency: Vec<u64> = (1..=100).collect();
+        latency.reverse();
+        for (i, millis) in latency.into_iter().enumerate() {
+            // 样本不够的时候不对冲
+            if i < MIN_SAMPLES {
+                assert_eq!(method.delay(), None);
+            }
+            method
+                .latency
+                .lock()
+                .unwrap()
+                .push_back(Duration::from_millis(millis));
+        }
+        assert_eq!(method.delay(), Some(Duration::from_millis(96)));
+    }
+
+    #[test]
+    fn observe_success_only() {
+        let method = method(None);
+        let start = Instant::now();
+        for _ in 0..LATENCY_SAMPLES + 10 {
+            let ok: Result<_, BoxError> = Ok(http::Response::new(tonic::body::empty_body()));
+            method.observe(&ok, start);
+        }
+        let failed = Status::unavailable("").into_http();
+        method.observe(&Ok::<_, BoxError>(failed), start);
+        method.observe(
+            &Err::<http::Response<BoxBody>, _>(BoxError::from("closed")),
+            start,
+        );
+        // 只保留最近的 LATENCY_SAMPLES 个成功的耗时
+        assert_eq!(method.latency.lock().unwrap().len(), LATENCY_SAMPLES);
+    }
+
+    #[test]
+    fn default_conf() {
+        // 不配置和配置为空的一样
+        let conf: HedgeConf = serde_yaml::from_str("Methods: {}").unwrap();
+        assert_eq!(conf.percent, HedgeConf::default().percent);
+        assert_eq!(ClientHedge::default().percent, conf.percent);
+    }
+}
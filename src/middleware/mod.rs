@@ -1 +1,8 @@
+pub mod deadline;
+pub mod hedge;
+pub mod retry;
+mod rolling_window;
+pub mod shedder;
 pub mod sre_breaker;
+mod stream_end;
+pub mod timeout;
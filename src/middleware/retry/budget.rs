@@ -0,0 +1,72 @@
+use crate::retry::RetryBudgetConf;
+use std::sync::Mutex;
+
+/// 令牌桶, 一开始是满的
+pub(crate) struct RetryBudget {
+    max_tokens: f64,
+    token_ratio: f64,
+    tokens: Mutex<f64>,
+}
+
+impl RetryBudget {
+    pub(crate) fn new(conf: RetryBudgetConf) -> Self {
+        Self {
+            max_tokens: conf.max_tokens,
+            token_ratio: conf.token_ratio,
+            tokens: Mutex::new(conf.max_tokens),
+        }
+    }
+
+    pub(crate) fn on_success(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens + self.token_ratio).min(self.max_tokens);
+    }
+
+    pub(crate) fn on_failure(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens - 1.0).max(0.0);
+    }
+
+    pub(crate) fn can_retry(&self) -> bool {
+        *self.tokens.lock().unwrap() > self.max_tokens / 2.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budget() {
+        let budget = RetryBudget::new(RetryBudgetConf {
+            max_tokens: 10.0,
+            token_ratio: 0.5,
+        });
+        assert!(budget.can_retry());
+        // 10 -> 6 还能重试, 再扣 1 个到 5 就不行了
+        for _ in 0..4 {
+            budget.on_failure();
+        }
+        assert!(budget.can_retry());
+        budget.on_failure();
+        assert!(!budget.can_retry());
+        // 扣到 0 就不再扣了, 要成功 11 次才能回到 5 以上
+        for _ in 0..100 {
+            budget.on_failure();
+        }
+        for _ in 0..10 {
+            budget.on_success();
+        }
+        assert!(!budget.can_retry());
+        budget.on_success();
+        assert!(budget.can_retry());
+        // 不超过上限
+        for _ in 0..100 {
+            budget.on_success();
+        }
+        for _ in 0..4 {
+            budget.on_failure();
+        }
+        assert!(budget.can_retry());
+    }
+}
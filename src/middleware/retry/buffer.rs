@@ -0,0 +1,80 @@
+use http_body::Frame;
+use pin_project_lite::pin_project;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::codegen::{Body, Bytes};
+use tonic::Status;
+
+/// 为了重发最多缓存这么多请求体, 和 grpc-go 的 retryBufferSize 一样
+const MAX_BUFFER_SIZE: usize = 256 * 1024;
+
+pub(crate) enum Buffered {
+    /// 整个请求体都读出来了, 可以重发
+    Complete(Bytes),
+    /// 流式调用或者请求体太大, 只能发一次
+    Streaming(BoxBody),
+}
+
+/// 缓存请求体用于重试、对冲. 一元调用的请求体编码好了马上就能读完; 需要等待的(流式调用还在发)
+/// 或者超过了 MAX_BUFFER_SIZE 的不再缓存, 已经读出来的部分和剩下的拼起来原样返回
+pub(crate) async fn buffer_body(mut body: BoxBody) -> Result<Buffered, Status> {
+    let mut buffer = Vec::new();
+    loop {
+        // 只拿已经准备好的帧, 不等待
+        let frame =
+            std::future::poll_fn(|cx| Poll::Ready(Pin::new(&mut body).poll_frame(cx))).await;
+        let pending = match frame {
+            Poll::Ready(Some(Ok(frame))) => match frame.into_data() {
+                Ok(data) if buffer.len() + data.len() <= MAX_BUFFER_SIZE => {
+                    buffer.extend_from_slice(&data);
+                    continue;
+                }
+                Ok(data) => Some(Frame::data(data)),
+                Err(frame) => Some(frame),
+            },
+            Poll::Ready(Some(Err(status))) => return Err(status),
+            Poll::Ready(None) => return Ok(Buffered::Complete(buffer.into())),
+            Poll::Pending => None,
+        };
+        let head = (!buffer.is_empty())
+            .then(|| Frame::data(Bytes::from(buffer)))
+            .into_iter()
+            .chain(pending)
+            .collect();
+        return Ok(Buffered::Streaming(tonic::body::boxed(ReplayBody {
+            head,
+            rest: body,
+        })));
+    }
+}
+
+pin_project! {
+    /// 先返回已经读出来的帧, 再接着读原来的请求体
+    struct ReplayBody {
+        head: VecDeque<Frame<Bytes>>,
+        #[pin]
+        rest: BoxBody,
+    }
+}
+
+impl Body for ReplayBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        if let Some(frame) = this.head.pop_front() {
+            return Poll::Ready(Some(Ok(frame)));
+        }
+        this.rest.poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.head.is_empty() && self.rest.is_end_stream()
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use tonic::Code;
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 1;
+const DEFAULT_INITIAL_BACKOFF: u64 = 50;
+const DEFAULT_MAX_BACKOFF: u64 = 1000;
+const DEFAULT_BACKOFF_MULTIPLIER: f64 = 2.0;
+const DEFAULT_MAX_TOKENS: f64 = 10.0;
+const DEFAULT_TOKEN_RATIO: f64 = 0.1;
+
+/// 一个方法的重试策略, 和 gRPC 的 retryPolicy 一样
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    // 包括第一次在内最多发几次, 默认 1 就是不重试. 不是幂等的方法不要配置
+    #[serde(rename = "MaxAttempts")]
+    pub max_attempts: u32,
+    // 第一次重试前最多等多久, 毫秒
+    #[serde(rename = "InitialBackoff")]
+    pub initial_backoff: u64,
+    // 重试前最多等多久, 毫秒
+    #[serde(rename = "MaxBackoff")]
+    pub max_backoff: u64,
+    #[serde(rename = "BackoffMultiplier")]
+    pub backoff_multiplier: f64,
+    // 可以重试的 gRPC 状态码, 默认只有 14(Unavailable)
+    #[serde(rename = "Codes")]
+    pub codes: Vec<i32>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            backoff_multiplier: DEFAULT_BACKOFF_MULTIPLIER,
+            codes: vec![Code::Unavailable as i32],
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub(crate) fn is_retryable(&self, code: Code) -> bool {
+        self.codes.contains(&(code as i32))
+    }
+
+    /// 第 attempt 次失败之后等多久, 在 0 到 min(initial * multiplier^(attempt-1), max) 之间随机
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self.initial_backoff as f64
+            * self
+                .backoff_multiplier
+                .powi(attempt.saturating_sub(1) as i32);
+        let backoff = backoff.min(self.max_backoff as f64) as usize;
+        if backoff == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_millis(crate::common::random(backoff + 1) as u64)
+    }
+}
+
+/// 重试预算, 和 gRPC 的 retryThrottling 一样. 每个失败扣 1 个令牌, 每个成功加 TokenRatio 个,
+/// 令牌不超过一半时不再重试, 下游大面积故障时避免重试风暴
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RetryBudgetConf {
+    #[serde(rename = "MaxTokens")]
+    pub max_tokens: f64,
+    #[serde(rename = "TokenRatio")]
+    pub token_ratio: f64,
+}
+
+impl Default for RetryBudgetConf {
+    fn default() -> Self {
+        Self {
+            max_tokens: DEFAULT_MAX_TOKENS,
+            token_ratio: DEFAULT_TOKEN_RATIO,
+        }
+    }
+}
+
+/// ClientRetry 的 yaml 配置, 放在 ClientConf 的 Retry 下面
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct RetryConf {
+    #[serde(flatten)]
+    pub policy: RetryPolicy,
+    #[serde(rename = "Budget", default)]
+    pub budget: RetryBudgetConf,
+    // 按方法覆盖
+    #[serde(rename = "Methods", default, skip_serializing_if = "HashMap::is_empty")]
+    pub methods: HashMap<String, MethodRetryConf>,
+}
+
+/// 单个方法的重试策略, 没有配置的参数用全局的
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct MethodRetryConf {
+    #[serde(rename = "MaxAttempts", skip_serializing_if = "Option::is_none")]
+    pub max_attempts: Option<u32>,
+    #[serde(rename = "InitialBackoff", skip_serializing_if = "Option::is_none")]
+    pub initial_backoff: Option<u64>,
+    #[serde(rename = "MaxBackoff", skip_serializing_if = "Option::is_none")]
+    pub max_backoff: Option<u64>,
+    #[serde(rename = "BackoffMultiplier", skip_serializing_if = "Option::is_none")]
+    pub backoff_multiplier: Option<f64>,
+    #[serde(rename = "Codes", skip_serializing_if = "Option::is_none")]
+    pub codes: Option<Vec<i32>>,
+}
+
+impl MethodRetryConf {
+    /// 和全局的策略合并
+    pub(crate) fn merge(&self, policy: &RetryPolicy) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.max_attempts.unwrap_or(policy.max_attempts),
+            initial_backoff: self.initial_backoff.unwrap_or(policy.initial_backoff),
+            max_backoff: self.max_backoff.unwrap_or(policy.max_backoff),
+            backoff_multiplier: self.backoff_multiplier.unwrap_or(policy.backoff_multiplier),
+            codes: self.codes.clone().unwrap_or_else(|| policy.codes.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_bounds() {
+        let policy = RetryPolicy {
+            initial_backoff: 100,
+            max_backoff: 500,
+            ..Default::default()
+        };
+        // 上限依次是 100, 200, 400, 500, 500
+        for (attempt, max) in [(1, 100), (2, 200), (3, 400), (4, 500), (10, 500)] {
+            let backoffs: Vec<Duration> = (0..200).map(|_| policy.backoff(attempt)).collect();
+            assert!(backoffs.iter().all(|b| *b <= Duration::from_millis(max)));
+            // 随机的, 不会都一样
+            assert!(backoffs.iter().any(|b| *b != backoffs[0]));
+        }
+    }
+
+    #[test]
+    fn zero_backoff() {
+        let policy = RetryPolicy {
+            initial_backoff: 0,
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(3), Duration::ZERO);
+    }
+
+    #[test]
+    fn merge_method_policy() {
+        let conf: RetryConf = serde_yaml::from_str(
+            "MaxAttempts: 2\nMethods:\n  /user.User/Get:\n    MaxAttempts: 4\n    Codes: [4, 14]\n",
+        )
+        .unwrap();
+        let policy = conf.methods["/user.User/Get"].merge(&conf.policy);
+        assert_eq!(policy.max_attempts, 4);
+        assert_eq!(policy.initial_backoff, DEFAULT_INITIAL_BACKOFF);
+        assert!(policy.is_retryable(Code::DeadlineExceeded));
+        assert!(!conf.policy.is_retryable(Code::DeadlineExceeded));
+        assert!(conf.policy.is_retryable(Code::Unavailable));
+    }
+}
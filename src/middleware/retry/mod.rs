@@ -0,0 +1,167 @@
+mod budget;
+mod buffer;
+mod conf;
+
+pub use conf::{MethodRetryConf, RetryBudgetConf, RetryConf, RetryPolicy};
+
+use crate::balance::TriedEndpoints;
+use budget::RetryBudget;
+pub(crate) use buffer::{buffer_body, Buffered};
+use http_body_util::Full;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::codegen::{http, BoxFuture, Bytes, Service};
+use tonic::{Code, Status};
+use tower::timeout::error::Elapsed;
+use tower::{BoxError, ServiceExt};
+
+/// 客户端的重试, 按方法配置可以重试的状态码、次数和退避时间, 所有方法共用一个重试预算.
+/// 默认不重试, 只有配置了 MaxAttempts 的才会重试. 重试会尽量发给没有发过的节点.
+/// 请求体会缓存下来用于重发, 流式调用和太大的请求体不缓存, 只发一次
+#[derive(Clone, Default)]
+pub struct ClientRetry {
+    policy: Arc<RetryPolicy>,
+    methods: Arc<HashMap<String, Arc<RetryPolicy>>>,
+    budget: RetryBudgetConf,
+}
+
+impl ClientRetry {
+    pub fn new(retry_conf: &RetryConf) -> Self {
+        let methods = retry_conf
+            .methods
+            .iter()
+            .map(|(path, method)| (path.clone(), Arc::new(method.merge(&retry_conf.policy))))
+            .collect();
+        Self {
+            policy: Arc::new(retry_conf.policy.clone()),
+            methods: Arc::new(methods),
+            budget: retry_conf.budget,
+        }
+    }
+}
+
+impl From<&RetryConf> for ClientRetry {
+    fn from(value: &RetryConf) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<S> tower::Layer<S> for ClientRetry {
+    type Service = ClientRetryInner<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ClientRetryInner {
+            inner: service,
+            retry: self.clone(),
+            budget: Arc::new(RetryBudget::new(self.budget)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ClientRetryInner<S> {
+    inner: S,
+    retry: ClientRetry,
+    budget: Arc<RetryBudget>,
+}
+
+impl<S> Service<http::Request<BoxBody>> for ClientRetryInner<S>
+where
+    S: Service<http::Request<BoxBody>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: http::Request<BoxBody>) -> Self::Future {
+        let policy = self
+            .retry
+            .methods
+            .get(req.uri().path())
+            .unwrap_or(&self.retry.policy)
+            .clone();
+        if policy.max_attempts <= 1 {
+            let future = self.inner.call(req);
+            return Box::pin(async move { future.await.map_err(Into::into) });
+        }
+        let inner = self.inner.clone();
+        let budget = self.budget.clone();
+        Box::pin(call_with_retry(inner, req, policy, budget))
+    }
+}
+
+async fn call_with_retry<S>(
+    inner: S,
+    req: http::Request<BoxBody>,
+    policy: Arc<RetryPolicy>,
+    budget: Arc<RetryBudget>,
+) -> Result<http::Response<BoxBody>, BoxError>
+where
+    S: Service<http::Request<BoxBody>, Response = http::Response<BoxBody>> + Clone,
+    S::Error: Into<BoxError>,
+{
+    let (parts, body) = req.into_parts();
+    let body = match buffer_body(body).await? {
+        Buffered::Complete(body) => body,
+        Buffered::Streaming(body) => {
+            let req = http::Request::from_parts(parts, body);
+            return inner.oneshot(req).await.map_err(Into::into);
+        }
+    };
+    // 外面的对冲已经放了的话共用, 对冲的两次尝试里面的重试也会换节点
+    let tried = parts
+        .extensions
+        .get::<TriedEndpoints>()
+        .cloned()
+        .unwrap_or_default();
+    let mut attempt = 1;
+    loop {
+        let mut req = clone_request(&parts, body.clone());
+        req.extensions_mut().insert(tried.clone());
+        let result = inner.clone().oneshot(req).await.map_err(Into::into);
+        // 拿到响应头之后 grpc-status 在 trailers 里的, 已经开始读响应了, 不再重试
+        let code = match &result {
+            Ok(response) => Status::from_header_map(response.headers()).map(|status| status.code()),
+            Err(err) => Some(error_code(err)),
+        };
+        if !code.is_some_and(|code| policy.is_retryable(code)) {
+            budget.on_success();
+            return result;
+        }
+        budget.on_failure();
+        if attempt >= policy.max_attempts || !budget.can_retry() {
+            return result;
+        }
+        tokio::time::sleep(policy.backoff(attempt)).await;
+        attempt += 1;
+    }
+}
+
+pub(crate) fn clone_request(parts: &http::request::Parts, body: Bytes) -> http::Request<BoxBody> {
+    let mut req = http::Request::new(tonic::body::boxed(Full::new(body)));
+    *req.method_mut() = parts.method.clone();
+    *req.uri_mut() = parts.uri.clone();
+    *req.version_mut() = parts.version;
+    *req.headers_mut() = parts.headers.clone();
+    *req.extensions_mut() = parts.extensions.clone();
+    req
+}
+
+/// 连接失败之类没有状态码的错误, 请求没有发到服务端, 按 Unavailable 处理
+fn error_code(err: &BoxError) -> Code {
+    if let Some(status) = err.downcast_ref::<Status>() {
+        return status.code();
+    }
+    if err.is::<Elapsed>() {
+        return Code::DeadlineExceeded;
+    }
+    Code::Unavailable
+}
@@ -0,0 +1,99 @@
+use std::time::{Duration, Instant};
+
+/// 按时间分桶的滑动窗口, 熔断器和降载共用. 每次访问时先把过期的桶清空
+pub(crate) struct RollingWindow<B> {
+    buckets: Vec<B>,
+    bucket_duration: Duration,
+    offset: usize,
+    // 当前桶的开始时间
+    start: Instant,
+}
+
+impl<B: Default + Clone> RollingWindow<B> {
+    pub(crate) fn new(window: Duration, buckets: usize) -> Self {
+        let buckets = buckets.max(1);
+        Self {
+            buckets: vec![B::default(); buckets],
+            bucket_duration: (window / buckets as u32).max(Duration::from_millis(1)),
+            offset: 0,
+            start: Instant::now(),
+        }
+    }
+
+    pub(crate) fn get_bucket_duration(&self) -> Duration {
+        self.bucket_duration
+    }
+
+    /// 把过期的桶清空, 移动到当前时间所在的桶
+    fn advance(&mut self) {
+        let elapsed = self.start.elapsed();
+        let span = (elapsed.as_nanos() / self.bucket_duration.as_nanos()) as usize;
+        if span == 0 {
+            return;
+        }
+        for i in 1..=span.min(self.buckets.len()) {
+            let index = (self.offset + i) % self.buckets.len();
+            self.buckets[index] = B::default();
+        }
+        self.offset = (self.offset + span) % self.buckets.len();
+        let remainder = elapsed.as_nanos() % self.bucket_duration.as_nanos();
+        self.start = Instant::now() - Duration::from_nanos(remainder as u64);
+    }
+
+    /// 当前时间所在的桶
+    pub(crate) fn current(&mut self) -> &mut B {
+        self.advance();
+        &mut self.buckets[self.offset]
+    }
+
+    /// 窗口里全部的桶, 包括还没有统计完的当前的桶
+    pub(crate) fn iter(&mut self) -> impl Iterator<Item = &B> {
+        self.advance();
+        self.buckets.iter()
+    }
+
+    /// 不算当前的桶, 当前的桶还没有统计完
+    pub(crate) fn completed(&mut self) -> impl Iterator<Item = &B> {
+        self.advance();
+        let offset = self.offset;
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(move |(index, _)| *index != offset)
+            .map(|(_, bucket)| bucket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rolling() {
+        // 4 个桶, 每个 25ms
+        let mut window: RollingWindow<u64> = RollingWindow::new(Duration::from_millis(100), 4);
+        assert_eq!(window.get_bucket_duration(), Duration::from_millis(25));
+        *window.current() += 1;
+        *window.current() += 1;
+        assert_eq!(window.iter().sum::<u64>(), 2);
+        assert_eq!(window.completed().sum::<u64>(), 0);
+
+        std::thread::sleep(Duration::from_millis(30));
+        *window.current() += 3;
+        assert_eq!(window.iter().sum::<u64>(), 5);
+        // 当前的桶还没有统计完, 不算
+        assert_eq!(window.completed().sum::<u64>(), 2);
+
+        // 超过整个窗口, 全部过期
+        std::thread::sleep(Duration::from_millis(120));
+        assert_eq!(window.iter().sum::<u64>(), 0);
+    }
+
+    #[test]
+    fn at_least_one_bucket() {
+        let mut window: RollingWindow<u64> = RollingWindow::new(Duration::from_millis(100), 0);
+        *window.current() += 1;
+        assert_eq!(window.iter().count(), 1);
+        assert_eq!(window.completed().count(), 0);
+    }
+}
@@ -0,0 +1,39 @@
+use crate::shedder::DEFAULT_MESSAGE;
+use tonic::Code;
+
+const DEFAULT_CPU_THRESHOLD: u64 = 900;
+const DEFAULT_WINDOW: u64 = 5000;
+const DEFAULT_BUCKETS: usize = 50;
+
+/// ServerShedder 的 yaml 配置
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ServerShedderConf {
+    // CPU 使用率超过这个值(千分比)才可能拒绝请求
+    #[serde(rename = "CpuThreshold")]
+    pub cpu_threshold: u64,
+    // 统计通过数和耗时的滑动窗口长度, 毫秒
+    #[serde(rename = "Window")]
+    pub window: u64,
+    // 滑动窗口分成多少个桶
+    #[serde(rename = "Buckets")]
+    pub buckets: usize,
+    // 拒绝时返回的 gRPC 状态码, 默认 8(ResourceExhausted)
+    #[serde(rename = "Code")]
+    pub code: i32,
+    // 拒绝时返回的错误信息
+    #[serde(rename = "Message")]
+    pub message: String,
+}
+
+impl Default for ServerShedderConf {
+    fn default() -> Self {
+        Self {
+            cpu_threshold: DEFAULT_CPU_THRESHOLD,
+            window: DEFAULT_WINDOW,
+            buckets: DEFAULT_BUCKETS,
+            code: Code::ResourceExhausted as i32,
+            message: DEFAULT_MESSAGE.to_owned(),
+        }
+    }
+}
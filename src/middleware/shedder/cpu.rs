@@ -0,0 +1,62 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Once;
+use std::time::{Duration, Instant};
+use tool::log::trace_log::info;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+/// 滑动平均的衰减系数, 越大越平滑
+const BETA: f64 = 0.95;
+/// /proc/self/stat 里 utime、stime 的单位, Linux 上给用户态看的值固定是 100
+const CLOCK_TICKS: f64 = 100.0;
+
+static CPU_USAGE: AtomicU64 = AtomicU64::new(0);
+static START: Once = Once::new();
+
+/// 进程最近的 CPU 使用率, 千分比, 所有可用的核都用满是 1000.
+/// 第一次调用时启动采样线程, 读不到 /proc 的系统上一直是 0
+pub(crate) fn get_cpu_usage() -> u64 {
+    START.call_once(|| {
+        let spawned = std::thread::Builder::new()
+            .name("zrpc-cpu-sampler".to_owned())
+            .spawn(sample);
+        if let Err(err) = spawned {
+            info!("start cpu sampler failed: {}", err);
+        }
+    });
+    CPU_USAGE.load(Ordering::Relaxed)
+}
+
+fn sample() {
+    let Some(mut last_ticks) = read_process_ticks() else {
+        info!("read /proc/self/stat failed, cpu based shedding is disabled");
+        return;
+    };
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get()) as f64;
+    let mut last_time = Instant::now();
+    let mut usage = 0.0;
+    loop {
+        std::thread::sleep(SAMPLE_INTERVAL);
+        let Some(ticks) = read_process_ticks() else {
+            continue;
+        };
+        let now = Instant::now();
+        let cpu_seconds = ticks.saturating_sub(last_ticks) as f64 / CLOCK_TICKS;
+        let elapsed = now.duration_since(last_time).as_secs_f64();
+        let current = (cpu_seconds / (elapsed * cores) * 1000.0).min(1000.0);
+        usage = usage * BETA + current * (1.0 - BETA);
+        CPU_USAGE.store(usage as u64, Ordering::Relaxed);
+        last_ticks = ticks;
+        last_time = now;
+    }
+}
+
+/// 进程用户态加内核态的 CPU 时间, 单位是 clock tick
+fn read_process_ticks() -> Option<u64> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // 进程名在括号里面, 可能有空格, 从最后一个括号之后开始数
+    let mut fields = stat.get(stat.rfind(')')? + 1..)?.split_whitespace();
+    // 括号后面第一个是 state(第 3 列), utime、stime 是第 14、15 列
+    let utime: u64 = fields.nth(11)?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some(utime + stime)
+}
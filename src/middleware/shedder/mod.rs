@@ -0,0 +1,364 @@
+mod conf;
+mod cpu;
+
+pub use conf::ServerShedderConf;
+
+use crate::middleware::rolling_window::RollingWindow;
+use crate::middleware::stream_end::{on_stream_end, StreamEnd};
+use cpu::get_cpu_usage;
+use pin_project_lite::pin_project;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tonic::body::BoxBody;
+use tonic::codegen::{http, Service};
+use tonic::{Code, Status};
+use tool::log::trace_log::error;
+
+/// 拒绝过请求之后, 这段时间内即使 CPU 降下来了也继续按过载处理, 避免来回抖动
+const COOL_OFF: Duration = Duration::from_secs(1);
+/// 进行中请求数滑动平均的衰减系数
+const FLYING_BETA: f64 = 0.9;
+/// 窗口里还没有耗时的时候用的默认值, 毫秒
+const DEFAULT_MIN_RT: f64 = 1000.0;
+/// 拒绝请求的日志最多这么久打一次, 和 go-zero 的 sheddingStat 一样
+const LOG_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_MESSAGE: &str = "服务过载，请稍后再试";
+
+#[derive(Default, Clone, Copy)]
+struct Bucket {
+    pass: u64,
+    // 毫秒
+    rt_sum: f64,
+}
+
+/// 单个桶里最多通过了多少个请求
+fn max_pass(window: &mut RollingWindow<Bucket>) -> u64 {
+    window
+        .completed()
+        .map(|bucket| bucket.pass)
+        .max()
+        .unwrap_or(0)
+        .max(1)
+}
+
+/// 单个桶里最小的平均耗时, 毫秒
+fn min_rt(window: &mut RollingWindow<Bucket>) -> f64 {
+    window
+        .completed()
+        .filter(|bucket| bucket.pass > 0)
+        .map(|bucket| bucket.rt_sum / bucket.pass as f64)
+        .reduce(f64::min)
+        .unwrap_or(DEFAULT_MIN_RT)
+}
+
+/// 和 go-zero 的 adaptiveShedder 一样: CPU 过载(或者刚拒绝过请求)的时候, 如果进行中的请求数
+/// 超过了估算的处理能力 maxPass * minRt, 就拒绝新的请求
+struct Shedder {
+    cpu_threshold: u64,
+    buckets_per_second: f64,
+    flying: AtomicI64,
+    avg_flying: Mutex<f64>,
+    window: Mutex<RollingWindow<Bucket>>,
+    drop_time: Mutex<Option<Instant>>,
+    // 上次打日志之后拒绝的请求数
+    dropped: AtomicU64,
+    last_log: Mutex<Option<Instant>>,
+    code: Code,
+    message: String,
+}
+
+impl Shedder {
+    fn new(conf: &ServerShedderConf) -> Self {
+        // 只看统计完的桶, 至少要两个
+        let window = RollingWindow::new(Duration::from_millis(conf.window), conf.buckets.max(2));
+        Self {
+            cpu_threshold: conf.cpu_threshold,
+            buckets_per_second: 1.0 / window.get_bucket_duration().as_secs_f64(),
+            flying: AtomicI64::new(0),
+            avg_flying: Mutex::new(0.0),
+            window: Mutex::new(window),
+            drop_time: Mutex::new(None),
+            dropped: AtomicU64::new(0),
+            last_log: Mutex::new(None),
+            code: Code::from_i32(conf.code),
+            message: conf.message.clone(),
+        }
+    }
+
+    /// 放行时返回 Promise, 请求结束时 drop 掉
+    fn allow(self: &Arc<Self>, cpu_usage: u64) -> Option<Promise> {
+        if self.should_drop(cpu_usage) {
+            *self.drop_time.lock().unwrap() = Some(Instant::now());
+            return None;
+        }
+        self.add_flying(1);
+        Some(Promise {
+            shedder: self.clone(),
+            start: Instant::now(),
+        })
+    }
+
+    fn should_drop(&self, cpu_usage: u64) -> bool {
+        if cpu_usage < self.cpu_threshold && !self.still_hot() {
+            return false;
+        }
+        let flying = self.flying.load(Ordering::Relaxed) as f64;
+        let avg_flying = *self.avg_flying.lock().unwrap();
+        let (max_pass, min_rt) = {
+            let mut window = self.window.lock().unwrap();
+            (max_pass(&mut window), min_rt(&mut window))
+        };
+        let max_flight = (max_pass as f64 * self.buckets_per_second * min_rt / 1000.0).max(1.0);
+        if flying <= max_flight || avg_flying <= max_flight {
+            return false;
+        }
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        // 过载的时候每个请求都打日志只会更糟, 汇总之后按间隔打
+        if let Ok(mut last_log) = self.last_log.try_lock() {
+            if last_log.is_none_or(|last_log| last_log.elapsed() >= LOG_INTERVAL) {
+                *last_log = Some(Instant::now());
+                error!(
+                    "dropped {} requests, cpu: {}, max_pass: {}, min_rt: {:.2}, flying: {}, avg_flying: {:.2}",
+                    self.dropped.swap(0, Ordering::Relaxed),
+                    cpu_usage,
+                    max_pass,
+                    min_rt,
+                    flying,
+                    avg_flying
+                );
+            }
+        }
+        true
+    }
+
+    fn reject_status(&self) -> Status {
+        Status::new(self.code, self.message.clone())
+    }
+
+    fn still_hot(&self) -> bool {
+        self.drop_time
+            .lock()
+            .unwrap()
+            .is_some_and(|drop_time| drop_time.elapsed() < COOL_OFF)
+    }
+
+    fn add_flying(&self, delta: i64) {
+        let flying = self.flying.fetch_add(delta, Ordering::Relaxed) + delta;
+        // 请求结束的时候更新平均值
+        if delta < 0 {
+            let mut avg_flying = self.avg_flying.lock().unwrap();
+            *avg_flying = *avg_flying * FLYING_BETA + flying as f64 * (1.0 - FLYING_BETA);
+        }
+    }
+}
+
+/// 放行的请求, drop 时减少进行中的请求数
+pub(crate) struct Promise {
+    shedder: Arc<Shedder>,
+    start: Instant,
+}
+
+impl Promise {
+    /// 处理完了, 记录通过数和耗时
+    fn pass(self) {
+        let rt = self.start.elapsed().as_secs_f64() * 1000.0;
+        let mut window = self.shedder.window.lock().unwrap();
+        let bucket = window.current();
+        bucket.pass += 1;
+        bucket.rt_sum += rt;
+    }
+}
+
+impl Drop for Promise {
+    fn drop(&mut self) {
+        self.shedder.add_flying(-1);
+    }
+}
+
+/// 服务端按 CPU 使用率自适应降载, 过载时默认返回 ResourceExhausted. 一个 layer 统计整个服务
+#[derive(Clone, Default)]
+pub struct ServerShedder {
+    conf: ServerShedderConf,
+}
+
+impl ServerShedder {
+    pub fn new(conf: ServerShedderConf) -> Self {
+        Self { conf }
+    }
+}
+
+impl From<&ServerShedderConf> for ServerShedder {
+    fn from(value: &ServerShedderConf) -> Self {
+        Self::new(value.clone())
+    }
+}
+
+impl<S> tower::Layer<S> for ServerShedder {
+    type Service = ServerShedderInner<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ServerShedderInner {
+            inner: service,
+            shedder: Arc::new(Shedder::new(&self.conf)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ServerShedderInner<S> {
+    inner: S,
+    shedder: Arc<Shedder>,
+}
+
+pin_project! {
+    #[project = ResponseFutureProj]
+    pub enum ResponseFuture<F> {
+        Admitted {
+            #[pin]
+            inner: F,
+            promise: Option<Promise>,
+        },
+        Rejected {
+            status: Option<Status>,
+        },
+    }
+}
+
+impl<S, ReqBody> Service<http::Request<ReqBody>> for ServerShedderInner<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<BoxBody>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<ReqBody>) -> Self::Future {
+        let Some(promise) = self.shedder.allow(get_cpu_usage()) else {
+            return ResponseFuture::Rejected {
+                status: Some(self.shedder.reject_status()),
+            };
+        };
+        ResponseFuture::Admitted {
+            inner: self.inner.call(req),
+            promise: Some(promise),
+        }
+    }
+}
+
+impl<F, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<http::Response<BoxBody>, E>>,
+{
+    type Output = Result<http::Response<BoxBody>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let (inner, promise) = match self.project() {
+            ResponseFutureProj::Admitted { inner, promise } => (inner, promise),
+            ResponseFutureProj::Rejected { status } => {
+                let status = status.take().expect("polled after completion");
+                return Poll::Ready(Ok(status.into_http()));
+            }
+        };
+        let result = match inner.poll(cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => return Poll::Pending,
+        };
+        let promise = promise.take().expect("polled after completion");
+        // 出错的只减少进行中的请求数, 不算通过; 正常的等响应流结束再记录, 流式调用的耗时也算在里面
+        Poll::Ready(result.map(|res| {
+            on_stream_end(res, move |end| {
+                if !matches!(end, StreamEnd::Error(_)) {
+                    promise.pass();
+                }
+            })
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 一个桶 100ms, 每秒 10 个桶; 窗口里没有统计时 max_pass 是 1, min_rt 是 1000ms, 能同时处理 10 个
+    fn shedder() -> Arc<Shedder> {
+        Arc::new(Shedder::new(&ServerShedderConf {
+            window: 1000,
+            buckets: 10,
+            ..Default::default()
+        }))
+    }
+
+    /// 进行中的请求数和它的平均值都到 flying
+    fn load(shedder: &Arc<Shedder>, flying: usize) -> Vec<Promise> {
+        let promises: Vec<Promise> = (0..flying).map(|_| shedder.allow(0).unwrap()).collect();
+        *shedder.avg_flying.lock().unwrap() = flying as f64;
+        promises
+    }
+
+    #[test]
+    fn pass_and_rt() {
+        let mut window: RollingWindow<Bucket> = RollingWindow::new(Duration::from_millis(100), 4);
+        // 没有统计完的桶
+        assert_eq!(max_pass(&mut window), 1);
+        assert_eq!(min_rt(&mut window), DEFAULT_MIN_RT);
+
+        *window.current() = Bucket {
+            pass: 4,
+            rt_sum: 40.0,
+        };
+        std::thread::sleep(Duration::from_millis(30));
+        *window.current() = Bucket {
+            pass: 2,
+            rt_sum: 10.0,
+        };
+        std::thread::sleep(Duration::from_millis(30));
+        // 当前的桶不算
+        *window.current() = Bucket {
+            pass: 100,
+            rt_sum: 1.0,
+        };
+        assert_eq!(max_pass(&mut window), 4);
+        assert_eq!(min_rt(&mut window), 5.0);
+    }
+
+    #[test]
+    fn drop_when_overloaded() {
+        let shedder = shedder();
+        let _promises = load(&shedder, 20);
+        assert!(shedder.allow(950).is_none());
+        assert_eq!(shedder.dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn no_drop() {
+        let shedder = shedder();
+        let _promises = load(&shedder, 20);
+        // CPU 没有过载
+        assert!(shedder.allow(500).is_some());
+
+        // 进行中的请求没有超过处理能力
+        let shedder = self::shedder();
+        let _promises = load(&shedder, 5);
+        assert!(shedder.allow(950).is_some());
+    }
+
+    #[test]
+    fn cool_off() {
+        let shedder = shedder();
+        let _promises = load(&shedder, 20);
+        assert!(shedder.allow(950).is_none());
+        // CPU 降下来了, 刚拒绝过请求还是继续拒绝
+        assert!(shedder.allow(500).is_none());
+        // 过了 COOL_OFF 就放行
+        *shedder.drop_time.lock().unwrap() = Some(Instant::now() - COOL_OFF);
+        assert!(shedder.allow(500).is_some());
+    }
+}
@@ -0,0 +1,87 @@
+use crate::middleware::stream_end::{on_stream_end, StreamEnd};
+use crate::sre_breaker::RouteBreaker;
+use std::sync::Arc;
+use tonic::body::BoxBody;
+use tonic::codegen::http;
+use tonic::Code;
+
+fn record(breaker: &RouteBreaker, code: Code) {
+    if breaker.classifier.is_failure(code) {
+        breaker.sre_breaker.mark_failed();
+    } else {
+        breaker.sre_breaker.mark_success();
+    }
+}
+
+/// 读到 grpc-status 或者流出错时记录结果, 调用方中途取消的不记录
+pub(crate) fn record_response(
+    breaker: Arc<RouteBreaker>,
+    response: http::Response<BoxBody>,
+) -> http::Response<BoxBody> {
+    on_stream_end(response, move |end| match end {
+        StreamEnd::Status(code) | StreamEnd::Error(code) => record(&breaker, code),
+        StreamEnd::Eof => breaker.sre_breaker.mark_failed(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::stream_end::tests::{data, read_to_end, response, trailers};
+    use crate::sre_breaker::breaker::SreBreaker;
+    use crate::sre_breaker::FailureClassifier;
+    use http_body_util::BodyExt;
+    use tonic::Status;
+
+    fn breaker() -> Arc<RouteBreaker> {
+        Arc::new(RouteBreaker {
+            sre_breaker: SreBreaker::default(),
+            classifier: FailureClassifier::default(),
+        })
+    }
+
+    #[tokio::test]
+    async fn success_in_trailers() {
+        let breaker = breaker();
+        let response = record_response(
+            breaker.clone(),
+            response([Ok(data()), Ok(data()), Ok(trailers(Code::Ok))]),
+        );
+        // 数据帧还没读完的时候不记录
+        assert_eq!(breaker.sre_breaker.get_counts(), (0, 0));
+        read_to_end(response).await;
+        assert_eq!(breaker.sre_breaker.get_counts(), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn failure_in_trailers() {
+        let breaker = breaker();
+        let response = record_response(
+            breaker.clone(),
+            response([Ok(data()), Ok(trailers(Code::Internal))]),
+        );
+        read_to_end(response).await;
+        assert_eq!(breaker.sre_breaker.get_counts(), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn trailers_only_error() {
+        let breaker = breaker();
+        // 状态在头里, 拿到响应就记录, 不用读 body
+        let _response = record_response(breaker.clone(), Status::unavailable("").into_http());
+        assert_eq!(breaker.sre_breaker.get_counts(), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn dropped_before_trailers() {
+        let breaker = breaker();
+        let response = record_response(
+            breaker.clone(),
+            response([Ok(data()), Ok(trailers(Code::Internal))]),
+        );
+        let mut body = response.into_body();
+        body.frame().await.unwrap().unwrap();
+        drop(body);
+        assert_eq!(breaker.sre_breaker.get_counts(), (0, 0));
+    }
+}
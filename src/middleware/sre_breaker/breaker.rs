@@ -0,0 +1,173 @@
+use crate::common::random;
+use crate::middleware::rolling_window::RollingWindow;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const DEFAULT_K: f64 = 1.5;
+const DEFAULT_WINDOW: u64 = 10000;
+const DEFAULT_BUCKETS: usize = 40;
+const DEFAULT_PROTECTION: u64 = 5;
+
+/// Google SRE 自适应限流的参数
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SreBreakerOptions {
+    // 倍数, 越小越容易拒绝, 一般 1.5 ~ 2
+    #[serde(rename = "K")]
+    pub k: f64,
+    // 统计的滑动窗口长度, 毫秒
+    #[serde(rename = "Window")]
+    pub window: u64,
+    // 滑动窗口分成多少个桶
+    #[serde(rename = "Buckets")]
+    pub buckets: usize,
+    // 窗口内的请求数不超过这个值时不会拒绝, 避免请求很少时误判
+    #[serde(rename = "Protection")]
+    pub protection: u64,
+}
+
+impl Default for SreBreakerOptions {
+    fn default() -> Self {
+        Self {
+            k: DEFAULT_K,
+            window: DEFAULT_WINDOW,
+            buckets: DEFAULT_BUCKETS,
+            protection: DEFAULT_PROTECTION,
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct Bucket {
+    requests: u64,
+    accepts: u64,
+}
+
+/// Google SRE 自适应限流: 按 max(0, (requests - protection - K * accepts) / (requests + 1)) 的概率拒绝.
+/// 后端正常时 accepts 接近 requests, 不会拒绝; 后端一直失败时拒绝的比例逐渐升高
+pub(crate) struct SreBreaker {
+    k: f64,
+    protection: u64,
+    window: Mutex<RollingWindow<Bucket>>,
+}
+
+impl SreBreaker {
+    pub(crate) fn new(options: SreBreakerOptions) -> Self {
+        Self {
+            k: options.k,
+            protection: options.protection,
+            window: Mutex::new(RollingWindow::new(
+                Duration::from_millis(options.window),
+                options.buckets,
+            )),
+        }
+    }
+
+    /// 被拒绝的请求也计入请求数, 拒绝的越多越难放行, 直到后端恢复
+    pub(crate) fn allow(&self) -> bool {
+        let mut window = self.window.lock().unwrap();
+        let (requests, accepts) = window.iter().fold((0, 0), |(requests, accepts), bucket| {
+            (requests + bucket.requests, accepts + bucket.accepts)
+        });
+        let drop_ratio = (requests as f64 - self.protection as f64 - self.k * accepts as f64)
+            / (requests + 1) as f64;
+        if drop_ratio <= 0.0 || random(10000) as f64 >= drop_ratio * 10000.0 {
+            return true;
+        }
+        window.current().requests += 1;
+        false
+    }
+
+    pub(crate) fn mark_success(&self) {
+        let mut window = self.window.lock().unwrap();
+        let bucket = window.current();
+        bucket.requests += 1;
+        bucket.accepts += 1;
+    }
+
+    pub(crate) fn mark_failed(&self) {
+        self.window.lock().unwrap().current().requests += 1;
+    }
+}
+
+impl Default for SreBreaker {
+    fn default() -> Self {
+        Self::new(SreBreakerOptions::default())
+    }
+}
+
+#[cfg(test)]
+impl SreBreaker {
+    /// 窗口里的请求数和成功数
+    pub(crate) fn get_counts(&self) -> (u64, u64) {
+        let mut window = self.window.lock().unwrap();
+        window.iter().fold((0, 0), |(requests, accepts), bucket| {
+            (requests + bucket.requests, accepts + bucket.accepts)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(window: u64) -> SreBreaker {
+        SreBreaker::new(SreBreakerOptions {
+            window,
+            buckets: 10,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn allow_within_protection() {
+        let breaker = breaker(10000);
+        for _ in 0..DEFAULT_PROTECTION {
+            breaker.mark_failed();
+        }
+        assert!((0..100).all(|_| breaker.allow()));
+    }
+
+    #[test]
+    fn allow_when_healthy() {
+        let breaker = breaker(10000);
+        for _ in 0..1000 {
+            assert!(breaker.allow());
+            breaker.mark_success();
+        }
+    }
+
+    #[test]
+    fn drop_when_failing() {
+        let breaker = breaker(10000);
+        for _ in 0..1000 {
+            breaker.mark_failed();
+        }
+        // 拒绝的概率 (1000 - 5) / 1001, 被拒绝的也计入请求数, 越来越难放行
+        let allowed = (0..1000).filter(|_| breaker.allow()).count();
+        assert!(allowed < 50, "{allowed}");
+    }
+
+    #[test]
+    fn drop_ratio_follows_accepts() {
+        let breaker = breaker(10000);
+        // 一半成功: (2000 - 5 - 1.5 * 1000) / 2001 ≈ 0.25
+        for _ in 0..1000 {
+            breaker.mark_success();
+            breaker.mark_failed();
+        }
+        let allowed = (0..1000).filter(|_| breaker.allow()).count();
+        assert!(allowed > 600 && allowed < 900, "{allowed}");
+    }
+
+    #[test]
+    fn recover_after_window() {
+        let breaker = breaker(100);
+        for _ in 0..1000 {
+            breaker.mark_failed();
+        }
+        assert!(!(0..100).all(|_| breaker.allow()));
+        std::thread::sleep(Duration::from_millis(150));
+        assert!((0..100).all(|_| breaker.allow()));
+    }
+}
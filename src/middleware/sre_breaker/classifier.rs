@@ -0,0 +1,74 @@
+use std::fmt;
+use std::sync::Arc;
+use tonic::Code;
+
+/// 默认只有这些错误算失败, 说明服务端扛不住或者出问题了.
+/// 参数错误、找不到之类的是调用方的问题, 不应该让熔断器打开
+pub fn default_is_failure(code: Code) -> bool {
+    matches!(
+        code,
+        Code::Unavailable
+            | Code::DeadlineExceeded
+            | Code::Internal
+            | Code::ResourceExhausted
+            | Code::Unknown
+    )
+}
+
+/// 判断一个响应的 gRPC 状态码是否记为熔断器的失败
+#[derive(Clone)]
+pub struct FailureClassifier(Arc<dyn Fn(Code) -> bool + Send + Sync>);
+
+impl FailureClassifier {
+    pub fn new(is_failure: impl Fn(Code) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(is_failure))
+    }
+
+    pub fn is_failure(&self, code: Code) -> bool {
+        (self.0)(code)
+    }
+}
+
+impl Default for FailureClassifier {
+    fn default() -> Self {
+        Self::new(default_is_failure)
+    }
+}
+
+impl fmt::Debug for FailureClassifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FailureClassifier")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_failures() {
+        let classifier = FailureClassifier::default();
+        for code in [
+            Code::Unavailable,
+            Code::DeadlineExceeded,
+            Code::Internal,
+            Code::ResourceExhausted,
+            Code::Unknown,
+        ] {
+            assert!(classifier.is_failure(code), "{code:?}");
+        }
+        // 调用方的问题不算
+        for code in [
+            Code::Ok,
+            Code::InvalidArgument,
+            Code::NotFound,
+            Code::AlreadyExists,
+            Code::PermissionDenied,
+            Code::Unauthenticated,
+            Code::FailedPrecondition,
+            Code::Cancelled,
+        ] {
+            assert!(!classifier.is_failure(code), "{code:?}");
+        }
+    }
+}
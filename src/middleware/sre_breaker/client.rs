@@ -0,0 +1,149 @@
+use crate::sre_breaker::body::record_response;
+use crate::sre_breaker::{
+    ClientSreBreakerBuilder, GroupConfig, SreBreakerBuilder, SreBreakerGroup,
+    DEFAULT_CLIENT_MESSAGE,
+};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::codegen::{http, BoxFuture, Service};
+use tower::BoxError;
+
+/// 客户端的自适应限流(Google SRE 熔断), 每个方法一个熔断器.
+/// 下游一直失败的时候在本地直接拒绝, 默认返回 Unavailable, 不再把请求发出去.
+/// 参数用 `ClientSreBreaker::builder()` 设置, 和服务端的一样
+#[derive(Clone)]
+pub struct ClientSreBreaker {
+    config: Arc<GroupConfig>,
+}
+
+impl ClientSreBreaker {
+    pub fn new() -> Self {
+        Self::builder().build()
+    }
+
+    pub fn builder() -> ClientSreBreakerBuilder {
+        SreBreakerBuilder::new(GroupConfig {
+            message: DEFAULT_CLIENT_MESSAGE.to_owned(),
+            ..Default::default()
+        })
+    }
+}
+
+impl Default for ClientSreBreaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClientSreBreakerBuilder {
+    pub fn build(self) -> ClientSreBreaker {
+        ClientSreBreaker {
+            config: Arc::new(self.config),
+        }
+    }
+}
+
+impl<S> tower::Layer<S> for ClientSreBreaker {
+    type Service = ClientSreBreakerInner<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ClientSreBreakerInner {
+            inner: service,
+            breaker: SreBreakerGroup::new(self.config.clone()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ClientSreBreakerInner<S> {
+    inner: S,
+    breaker: SreBreakerGroup,
+}
+
+impl<S> Service<http::Request<BoxBody>> for ClientSreBreakerInner<S>
+where
+    S: Service<http::Request<BoxBody>, Response = http::Response<BoxBody>>,
+    S::Future: Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: http::Request<BoxBody>) -> Self::Future {
+        // 排除了的方法直接发出去
+        let Some(breaker) = self.breaker.get_route_breaker(req.uri().path()) else {
+            let future = self.inner.call(req);
+            return Box::pin(async move { future.await.map_err(Into::into) });
+        };
+        if !breaker.sre_breaker.allow() {
+            let status = self.breaker.reject_status();
+            return Box::pin(async move { Err(status.into()) });
+        }
+        let future = self.inner.call(req);
+        Box::pin(async move {
+            match future.await {
+                Ok(response) => Ok(record_response(breaker, response)),
+                Err(err) => {
+                    // 连接失败之类的
+                    breaker.sre_breaker.mark_failed();
+                    Err(err.into())
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tonic::body::empty_body;
+    use tonic::{Code, Status};
+    use tower::{Layer, ServiceExt};
+
+    #[tokio::test]
+    async fn fail_fast_on_unavailable() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let inner_calls = calls.clone();
+        let inner = tower::service_fn(move |_request: http::Request<BoxBody>| {
+            inner_calls.fetch_add(1, Ordering::Relaxed);
+            async { Ok::<_, BoxError>(Status::unavailable("down").into_http()) }
+        });
+        let breaker = ClientSreBreaker::builder()
+            .protection(0)
+            .code(Code::ResourceExhausted)
+            .message("busy")
+            .build();
+        let mut service = breaker.layer(inner);
+
+        let mut rejected = 0;
+        for _ in 0..100 {
+            let request = http::Request::builder()
+                .uri("/test.User/Get")
+                .body(empty_body())
+                .unwrap();
+            match service.ready().await.unwrap().call(request).await {
+                // 发出去了, 下游返回 Unavailable
+                Ok(response) => assert_eq!(
+                    Status::from_header_map(response.headers()).unwrap().code(),
+                    Code::Unavailable
+                ),
+                // 本地直接拒绝
+                Err(err) => {
+                    let status = err.downcast::<Status>().unwrap();
+                    assert_eq!(status.code(), Code::ResourceExhausted);
+                    assert_eq!(status.message(), "busy");
+                    rejected += 1;
+                }
+            }
+        }
+        assert!(rejected > 50, "{rejected}");
+        assert_eq!(calls.load(Ordering::Relaxed), 100 - rejected);
+    }
+}
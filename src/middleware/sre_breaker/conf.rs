@@ -0,0 +1,66 @@
+use crate::sre_breaker::{SreBreakerOptions, DEFAULT_MESSAGE};
+use std::collections::HashMap;
+use tonic::Code;
+
+/// ServerSreBreaker 的 yaml 配置
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ServerSreBreakerConf {
+    #[serde(flatten)]
+    pub options: SreBreakerOptions,
+    // 拒绝时返回的 gRPC 状态码, 默认 14(Unavailable)
+    #[serde(rename = "Code")]
+    pub code: i32,
+    // 拒绝时返回的错误信息
+    #[serde(rename = "Message")]
+    pub message: String,
+    // 按方法覆盖
+    #[serde(rename = "Methods", skip_serializing_if = "HashMap::is_empty")]
+    pub methods: HashMap<String, MethodSreBreakerConf>,
+}
+
+impl Default for ServerSreBreakerConf {
+    fn default() -> Self {
+        Self {
+            options: SreBreakerOptions::default(),
+            code: Code::Unavailable as i32,
+            message: DEFAULT_MESSAGE.to_owned(),
+            methods: HashMap::new(),
+        }
+    }
+}
+
+/// 单个方法的配置, 没有配置的参数用全局的
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct MethodSreBreakerConf {
+    // 不经过熔断器, 比如健康检查
+    #[serde(rename = "Exclude", default)]
+    pub exclude: bool,
+    #[serde(rename = "K", skip_serializing_if = "Option::is_none")]
+    pub k: Option<f64>,
+    #[serde(rename = "Window", skip_serializing_if = "Option::is_none")]
+    pub window: Option<u64>,
+    #[serde(rename = "Buckets", skip_serializing_if = "Option::is_none")]
+    pub buckets: Option<usize>,
+    #[serde(rename = "Protection", skip_serializing_if = "Option::is_none")]
+    pub protection: Option<u64>,
+}
+
+impl MethodSreBreakerConf {
+    /// 和全局的参数合并, 一个都没有配置时返回 None
+    pub(crate) fn merge(&self, options: &SreBreakerOptions) -> Option<SreBreakerOptions> {
+        if self.k.is_none()
+            && self.window.is_none()
+            && self.buckets.is_none()
+            && self.protection.is_none()
+        {
+            return None;
+        }
+        Some(SreBreakerOptions {
+            k: self.k.unwrap_or(options.k),
+            window: self.window.unwrap_or(options.window),
+            buckets: self.buckets.unwrap_or(options.buckets),
+            protection: self.protection.unwrap_or(options.protection),
+        })
+    }
+}
@@ -1,45 +1,154 @@
+mod body;
+mod breaker;
+mod classifier;
+mod client;
+mod conf;
+
+pub use breaker::SreBreakerOptions;
+pub use classifier::{default_is_failure, FailureClassifier};
+pub use client::{ClientSreBreaker, ClientSreBreakerInner};
+pub use conf::{MethodSreBreakerConf, ServerSreBreakerConf};
+
+use body::record_response;
+use breaker::SreBreaker;
 use dashmap::DashMap;
 use pin_project_lite::pin_project;
+use std::collections::HashMap;
 use std::future::Future;
+use std::marker::PhantomData;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use tonic::body::BoxBody;
 use tonic::codegen::{http, Service};
-use tonic::Status;
-use tool::sre_breaker::breaker::SreBreaker;
-
-#[derive(Debug, Clone)]
-struct ServerSreBreakerGroup(Arc<DashMap<String, Arc<SreBreaker>>>);
-
-impl ServerSreBreakerGroup {
-    fn get_sre_breaker(&self, uri_path: String) -> Arc<SreBreaker> {
-        let ref_mut = self
-            .0
-            .entry(uri_path)
-            .or_insert_with(|| Arc::new(SreBreaker::default()));
+use tonic::{Code, Status};
+
+const DEFAULT_MESSAGE: &str = "系统繁忙，请稍后再试";
+const DEFAULT_CLIENT_MESSAGE: &str = "下游服务繁忙，请稍后再试";
+
+/// 一个路由的熔断器, 以及判断这个路由失败的方式
+struct RouteBreaker {
+    sre_breaker: SreBreaker,
+    classifier: FailureClassifier,
+}
+
+/// 单个方法覆盖的配置
+#[derive(Clone, Default)]
+struct MethodBreaker {
+    exclude: bool,
+    options: Option<SreBreakerOptions>,
+    classifier: Option<FailureClassifier>,
+}
+
+struct GroupConfig {
+    options: SreBreakerOptions,
+    classifier: FailureClassifier,
+    methods: HashMap<String, MethodBreaker>,
+    code: Code,
+    message: String,
+}
+
+impl Default for GroupConfig {
+    fn default() -> Self {
+        Self {
+            options: SreBreakerOptions::default(),
+            classifier: FailureClassifier::default(),
+            methods: HashMap::new(),
+            code: Code::Unavailable,
+            message: DEFAULT_MESSAGE.to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct SreBreakerGroup {
+    // 排除了的方法是 None
+    breakers: Arc<DashMap<String, Option<Arc<RouteBreaker>>>>,
+    config: Arc<GroupConfig>,
+}
+
+impl SreBreakerGroup {
+    fn new(config: Arc<GroupConfig>) -> Self {
+        Self {
+            breakers: Arc::new(DashMap::new()),
+            config,
+        }
+    }
+
+    /// 每个路由第一次请求时创建, 之后只是一次读锁的查找, 不用分配 path 的 String
+    fn get_route_breaker(&self, uri_path: &str) -> Option<Arc<RouteBreaker>> {
+        if let Some(breaker) = self.breakers.get(uri_path) {
+            return breaker.value().clone();
+        }
+        let ref_mut = self.breakers.entry(uri_path.to_owned()).or_insert_with(|| {
+            let method = self.config.methods.get(uri_path);
+            if method.is_some_and(|method| method.exclude) {
+                return None;
+            }
+            let options = method
+                .and_then(|method| method.options)
+                .unwrap_or(self.config.options);
+            let classifier = method
+                .and_then(|method| method.classifier.as_ref())
+                .unwrap_or(&self.config.classifier);
+            Some(Arc::new(RouteBreaker {
+                sre_breaker: SreBreaker::new(options),
+                classifier: classifier.clone(),
+            }))
+        });
         ref_mut.value().clone()
         // note: 锁在这里释放
     }
+
+    fn reject_status(&self) -> Status {
+        Status::new(self.config.code, self.config.message.clone())
+    }
 }
 
 #[derive(Clone)]
 pub struct ServerSreBreakerInner<S> {
     inner: S,
-    breaker: ServerSreBreakerGroup,
+    breaker: SreBreakerGroup,
 }
 
 pin_project! {
-    pub struct ResponseFuture<F> {
-        #[pin]
-        inner: F,
-        breaker: ServerSreBreakerGroup,
-        full_uri_path: String,
+    /// 是否放行在 call 里就决定了, 被拒绝的请求不会调用到 handler
+    #[project = ResponseFutureProj]
+    pub enum ResponseFuture<F> {
+        Admitted {
+            #[pin]
+            inner: F,
+            // 排除了的方法不记录
+            breaker: Option<Arc<RouteBreaker>>,
+        },
+        Rejected {
+            status: Option<Status>,
+        },
     }
 }
 
-#[derive(Clone)]
-pub struct ServerSreBreaker;
+/// 服务端的自适应限流, 每个方法一个熔断器. 参数用 `ServerSreBreaker::builder()` 或者配置文件设置
+#[derive(Clone, Default)]
+pub struct ServerSreBreaker {
+    config: Arc<GroupConfig>,
+}
+
+impl ServerSreBreaker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn builder() -> ServerSreBreakerBuilder {
+        SreBreakerBuilder::new(GroupConfig::default())
+    }
+}
+
+impl From<&ServerSreBreakerConf> for ServerSreBreaker {
+    fn from(value: &ServerSreBreakerConf) -> Self {
+        ServerSreBreakerBuilder::from(value).build()
+    }
+}
 
 impl<S> tower::Layer<S> for ServerSreBreaker {
     type Service = ServerSreBreakerInner<S>;
@@ -47,11 +156,123 @@ impl<S> tower::Layer<S> for ServerSreBreaker {
     fn layer(&self, service: S) -> Self::Service {
         ServerSreBreakerInner {
             inner: service,
-            breaker: ServerSreBreakerGroup(Arc::new(DashMap::new())),
+            breaker: SreBreakerGroup::new(self.config.clone()),
         }
     }
 }
 
+/// 服务端和客户端的熔断器共用的 builder
+pub struct SreBreakerBuilder<T> {
+    config: GroupConfig,
+    _target: PhantomData<T>,
+}
+
+pub type ServerSreBreakerBuilder = SreBreakerBuilder<ServerSreBreaker>;
+pub type ClientSreBreakerBuilder = SreBreakerBuilder<ClientSreBreaker>;
+
+impl<T> SreBreakerBuilder<T> {
+    fn new(config: GroupConfig) -> Self {
+        Self {
+            config,
+            _target: PhantomData,
+        }
+    }
+
+    /// 倍数, 越小越容易拒绝, 默认 1.5
+    pub fn k(mut self, k: f64) -> Self {
+        self.config.options.k = k;
+        self
+    }
+
+    /// 统计的滑动窗口长度, 默认 10 秒
+    pub fn window(mut self, window: Duration) -> Self {
+        self.config.options.window = window.as_millis() as u64;
+        self
+    }
+
+    /// 滑动窗口分成多少个桶, 默认 40
+    pub fn buckets(mut self, buckets: usize) -> Self {
+        self.config.options.buckets = buckets;
+        self
+    }
+
+    /// 窗口内的请求数不超过这个值时不会拒绝, 默认 5
+    pub fn protection(mut self, protection: u64) -> Self {
+        self.config.options.protection = protection;
+        self
+    }
+
+    /// 拒绝时返回的状态码, 默认 Unavailable
+    pub fn code(mut self, code: Code) -> Self {
+        self.config.code = code;
+        self
+    }
+
+    /// 拒绝时返回的错误信息
+    pub fn message(mut self, message: impl Into<String>) -> Self {
+        self.config.message = message.into();
+        self
+    }
+
+    /// 替换默认的失败判断, 默认见 `default_is_failure`
+    pub fn classifier(mut self, classifier: FailureClassifier) -> Self {
+        self.config.classifier = classifier;
+        self
+    }
+
+    fn method(&mut self, path: impl Into<String>) -> &mut MethodBreaker {
+        self.config.methods.entry(path.into()).or_default()
+    }
+
+    /// 单独设置某个方法的参数, path 是 `/package.Service/Method`
+    pub fn method_options(mut self, path: impl Into<String>, options: SreBreakerOptions) -> Self {
+        self.method(path).options = Some(options);
+        self
+    }
+
+    /// 单独设置某个方法的失败判断
+    pub fn method_classifier(
+        mut self,
+        path: impl Into<String>,
+        classifier: FailureClassifier,
+    ) -> Self {
+        self.method(path).classifier = Some(classifier);
+        self
+    }
+
+    /// 这个方法不经过熔断器, 比如 `/grpc.health.v1.Health/Check`
+    pub fn exclude(mut self, path: impl Into<String>) -> Self {
+        self.method(path).exclude = true;
+        self
+    }
+}
+
+impl ServerSreBreakerBuilder {
+    pub fn build(self) -> ServerSreBreaker {
+        ServerSreBreaker {
+            config: Arc::new(self.config),
+        }
+    }
+}
+
+impl From<&ServerSreBreakerConf> for ServerSreBreakerBuilder {
+    fn from(value: &ServerSreBreakerConf) -> Self {
+        let mut builder = ServerSreBreaker::builder()
+            .k(value.options.k)
+            .window(Duration::from_millis(value.options.window))
+            .buckets(value.options.buckets)
+            .protection(value.options.protection)
+            .code(Code::from_i32(value.code))
+            .message(&value.message);
+        for (path, method_conf) in &value.methods {
+            let method = builder.method(path);
+            method.exclude = method_conf.exclude;
+            method.options = method_conf.merge(&value.options);
+        }
+        builder
+    }
+}
+
 impl<S, ReqBody> Service<http::Request<ReqBody>> for ServerSreBreakerInner<S>
 where
     S: Service<http::Request<ReqBody>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
@@ -68,13 +289,20 @@ where
     }
 
     fn call(&mut self, req: http::Request<ReqBody>) -> Self::Future {
+        let breaker = self.breaker.get_route_breaker(req.uri().path());
+        if breaker
+            .as_ref()
+            .is_some_and(|breaker| !breaker.sre_breaker.allow())
+        {
+            return ResponseFuture::Rejected {
+                status: Some(self.breaker.reject_status()),
+            };
+        }
         let clone = self.inner.clone();
         let mut inner = std::mem::replace(&mut self.inner, clone);
-        let full_uri_path = req.uri().path().to_owned();
-        ResponseFuture {
-            breaker: self.breaker.clone(),
-            full_uri_path,
+        ResponseFuture::Admitted {
             inner: inner.call(req),
+            breaker,
         }
     }
 }
@@ -87,31 +315,69 @@ where
     type Output = Result<http::Response<BoxBody>, E>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let this = self.project();
-        let sre_breaker = this.breaker.get_sre_breaker(this.full_uri_path.clone());
-        if sre_breaker.allow().is_err() {
-            let error = Status::unavailable("系统繁忙，请稍后再试");
-            return Poll::Ready(Ok(error.into_http()));
+        let (inner, breaker) = match self.project() {
+            ResponseFutureProj::Admitted { inner, breaker } => (inner, breaker),
+            ResponseFutureProj::Rejected { status } => {
+                let status = status.take().expect("polled after completion");
+                return Poll::Ready(Ok(status.into_http()));
+            }
+        };
+        let Some(breaker) = breaker else {
+            return inner.poll(cx);
         };
-        if let Poll::Ready(res) = this.inner.poll(cx) {
-            use std::borrow::Borrow;
-            if let Ok(res) = res.borrow() {
-                let res_status = Status::from_header_map(res.headers());
-                if let Some(value) = res_status {
-                    // TODO: 目前是只要不是成功的就算失败, 这里需要好好考虑一下
-                    if value.code() != tonic::Code::Ok {
-                        sre_breaker.mark_failed();
-                    } else {
-                        sre_breaker.mark_success();
-                    }
-                } else {
-                    sre_breaker.mark_failed();
-                }
-            } else {
-                sre_breaker.mark_failed();
+        match inner.poll(cx) {
+            // grpc-status 一般在 trailers 里, 等响应流结束再记录
+            Poll::Ready(Ok(res)) => Poll::Ready(Ok(record_response(breaker.clone(), res))),
+            Poll::Ready(Err(err)) => {
+                breaker.sre_breaker.mark_failed();
+                Poll::Ready(Err(err))
             }
-            return Poll::Ready(res);
+            Poll::Pending => Poll::Pending,
         }
-        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(builder: ServerSreBreakerBuilder) -> SreBreakerGroup {
+        SreBreakerGroup::new(Arc::new(builder.config))
+    }
+
+    #[test]
+    fn method_classifier_first() {
+        let group = group(
+            ServerSreBreaker::builder()
+                .classifier(FailureClassifier::new(|code| code != Code::Ok))
+                .method_classifier(
+                    "/user.User/Get",
+                    FailureClassifier::new(|code| code == Code::NotFound),
+                ),
+        );
+        let get = group.get_route_breaker("/user.User/Get").unwrap();
+        assert!(get.classifier.is_failure(Code::NotFound));
+        assert!(!get.classifier.is_failure(Code::Internal));
+        // 没有单独设置的方法用整体的
+        let add = group.get_route_breaker("/user.User/Add").unwrap();
+        assert!(add.classifier.is_failure(Code::InvalidArgument));
+    }
+
+    #[test]
+    fn default_classifier() {
+        let group = group(ServerSreBreaker::builder());
+        let breaker = group.get_route_breaker("/user.User/Get").unwrap();
+        assert!(!breaker.classifier.is_failure(Code::InvalidArgument));
+        assert!(!breaker.classifier.is_failure(Code::NotFound));
+        assert!(breaker.classifier.is_failure(Code::Unavailable));
+    }
+
+    #[test]
+    fn exclude() {
+        let group = group(ServerSreBreaker::builder().exclude("/grpc.health.v1.Health/Check"));
+        assert!(group
+            .get_route_breaker("/grpc.health.v1.Health/Check")
+            .is_none());
+        assert!(group.get_route_breaker("/user.User/Get").is_some());
     }
 }
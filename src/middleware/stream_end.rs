@@ -0,0 +1,184 @@
+use http_body::Frame;
+use pin_project_lite::pin_project;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::codegen::{http, Body, Bytes};
+use tonic::{Code, Status};
+
+/// 响应流是怎么结束的
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StreamEnd {
+    /// trailers 里的 grpc-status, 没有的按 Ok 算, 和 tonic 的处理一致
+    Status(Code),
+    /// 读流的时候出错
+    Error(Code),
+    /// 没有 trailers 就结束了, 不是正常的 gRPC 响应
+    Eof,
+}
+
+/// 响应流结束时调用 on_end. trailers-only 的响应(一般是出错了)状态在头里, 已经结束了, 直接调用;
+/// 其他的包一层 body 等流结束时再调用. 调用方没读完 body 就 drop 掉的不调用
+pub(crate) fn on_stream_end<F>(
+    response: http::Response<BoxBody>,
+    on_end: F,
+) -> http::Response<BoxBody>
+where
+    F: FnOnce(StreamEnd) + Send + 'static,
+{
+    if let Some(status) = Status::from_header_map(response.headers()) {
+        on_end(StreamEnd::Status(status.code()));
+        return response;
+    }
+    response.map(|body| {
+        tonic::body::boxed(StreamEndBody {
+            inner: body,
+            on_end: Some(on_end),
+        })
+    })
+}
+
+pin_project! {
+    struct StreamEndBody<B, F> {
+        #[pin]
+        inner: B,
+        // 调用过之后就是 None
+        on_end: Option<F>,
+    }
+}
+
+impl<B, F> Body for StreamEndBody<B, F>
+where
+    B: Body<Data = Bytes, Error = Status>,
+    F: FnOnce(StreamEnd),
+{
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let frame = match this.inner.poll_frame(cx) {
+            Poll::Ready(frame) => frame,
+            Poll::Pending => return Poll::Pending,
+        };
+        let end = match &frame {
+            // 数据帧的时候是 None, 接着等
+            Some(Ok(frame)) => frame.trailers_ref().map(|trailers| {
+                StreamEnd::Status(Status::from_header_map(trailers).map_or(Code::Ok, |s| s.code()))
+            }),
+            Some(Err(status)) => Some(StreamEnd::Error(status.code())),
+            None => Some(StreamEnd::Eof),
+        };
+        if let Some(end) = end {
+            if let Some(on_end) = this.on_end.take() {
+                on_end(end);
+            }
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    /// 按顺序返回这些帧的 body
+    struct Frames(VecDeque<Result<Frame<Bytes>, Status>>);
+
+    impl Body for Frames {
+        type Data = Bytes;
+        type Error = Status;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+            Poll::Ready(self.0.pop_front())
+        }
+    }
+
+    pub(crate) fn data() -> Frame<Bytes> {
+        Frame::data(Bytes::from_static(b"data"))
+    }
+
+    pub(crate) fn trailers(code: Code) -> Frame<Bytes> {
+        let mut trailers = http::HeaderMap::new();
+        trailers.insert("grpc-status", (code as i32).into());
+        Frame::trailers(trailers)
+    }
+
+    pub(crate) fn response(
+        frames: impl IntoIterator<Item = Result<Frame<Bytes>, Status>>,
+    ) -> http::Response<BoxBody> {
+        http::Response::new(tonic::body::boxed(Frames(frames.into_iter().collect())))
+    }
+
+    /// 把 body 读到结束或者出错
+    pub(crate) async fn read_to_end(response: http::Response<BoxBody>) {
+        let mut body = response.into_body();
+        while let Some(Ok(_)) = body.frame().await {}
+    }
+
+    fn watch(
+        response: http::Response<BoxBody>,
+    ) -> (http::Response<BoxBody>, Arc<Mutex<Vec<StreamEnd>>>) {
+        let ends = Arc::new(Mutex::new(Vec::new()));
+        let recorded = ends.clone();
+        let response = on_stream_end(response, move |end| recorded.lock().unwrap().push(end));
+        (response, ends)
+    }
+
+    #[tokio::test]
+    async fn stream_end() {
+        let cases = [
+            (
+                vec![Ok(data()), Ok(trailers(Code::Ok))],
+                StreamEnd::Status(Code::Ok),
+            ),
+            (
+                vec![Ok(data()), Ok(trailers(Code::Internal))],
+                StreamEnd::Status(Code::Internal),
+            ),
+            (
+                vec![Ok(data()), Err(Status::unavailable("reset"))],
+                StreamEnd::Error(Code::Unavailable),
+            ),
+            (vec![Ok(data())], StreamEnd::Eof),
+        ];
+        for (frames, expected) in cases {
+            let (response, ends) = watch(response(frames));
+            read_to_end(response).await;
+            assert_eq!(*ends.lock().unwrap(), [expected]);
+        }
+    }
+
+    #[tokio::test]
+    async fn trailers_only() {
+        let response = Status::not_found("").into_http();
+        let (_response, ends) = watch(response);
+        assert_eq!(*ends.lock().unwrap(), [StreamEnd::Status(Code::NotFound)]);
+    }
+
+    #[tokio::test]
+    async fn dropped_before_end() {
+        let (response, ends) = watch(response([Ok(data()), Ok(trailers(Code::Ok))]));
+        let mut body = response.into_body();
+        body.frame().await.unwrap().unwrap();
+        drop(body);
+        assert!(ends.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,270 @@
+use crate::deadline::{get_timeout, with_deadline};
+use crate::etcd::register::ServerConf;
+use pin_project_lite::pin_project;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::task::futures::TaskLocalFuture;
+use tokio::time::Sleep;
+use tonic::body::BoxBody;
+use tonic::codegen::{http, Service};
+use tonic::Status;
+use tool::log::trace_log::error;
+
+/// 服务端 handler 的超时, 可以按方法覆盖默认的超时. 超时后 drop 掉 handler 的 future,
+/// 返回 DeadlineExceeded. 也会缩短 task-local 里的截止时间, handler 里调用别的服务时带上
+#[derive(Clone, Default)]
+pub struct ServerTimeout {
+    timeout: Option<Duration>,
+    methods: Arc<HashMap<String, Duration>>,
+}
+
+impl ServerTimeout {
+    pub fn new(timeout: Option<Duration>, methods: HashMap<String, Duration>) -> Self {
+        Self {
+            timeout,
+            methods: Arc::new(methods),
+        }
+    }
+
+    fn get_timeout(&self, path: &str) -> Option<Duration> {
+        self.methods.get(path).copied().or(self.timeout)
+    }
+}
+
+/// 0 毫秒的话每个请求一到就超时, 肯定是配错了, 忽略掉
+fn non_zero(name: &str, timeout: u64) -> Option<Duration> {
+    if timeout == 0 {
+        error!("ignore zero timeout of {}", name);
+        return None;
+    }
+    Some(Duration::from_millis(timeout))
+}
+
+impl From<&ServerConf> for ServerTimeout {
+    fn from(value: &ServerConf) -> Self {
+        let methods = value
+            .get_method_timeouts()
+            .iter()
+            .filter_map(|(path, timeout)| Some((path.clone(), non_zero(path, *timeout)?)))
+            .collect();
+        let timeout = value
+            .get_timeout()
+            .and_then(|timeout| non_zero("Timeout", timeout));
+        Self::new(timeout, methods)
+    }
+}
+
+impl<S> tower::Layer<S> for ServerTimeout {
+    type Service = ServerTimeoutInner<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ServerTimeoutInner {
+            inner: service,
+            timeout: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ServerTimeoutInner<S> {
+    inner: S,
+    timeout: ServerTimeout,
+}
+
+pin_project! {
+    #[project = ResponseFutureProj]
+    pub enum ResponseFuture<F> {
+        Limited {
+            #[pin]
+            inner: TaskLocalFuture<Instant, F>,
+            #[pin]
+            sleep: Sleep,
+            path: String,
+            timeout: Duration,
+        },
+        // 没有配置超时的方法
+        Unlimited {
+            #[pin]
+            inner: F,
+        },
+    }
+}
+
+impl<S, ReqBody> Service<http::Request<ReqBody>> for ServerTimeoutInner<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<BoxBody>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<ReqBody>) -> Self::Future {
+        let path = req.uri().path();
+        let Some(timeout) = self.timeout.get_timeout(path) else {
+            return ResponseFuture::Unlimited {
+                inner: self.inner.call(req),
+            };
+        };
+        let path = path.to_owned();
+        // 调用方给的时间更短的话用调用方的, 截止时间和超时用同一个值
+        let timeout =
+            get_timeout(req.headers()).map_or(timeout, |grpc_timeout| grpc_timeout.min(timeout));
+        let deadline = Instant::now() + timeout;
+        ResponseFuture::Limited {
+            inner: with_deadline(deadline, self.inner.call(req)),
+            sleep: tokio::time::sleep_until(deadline.into()),
+            path,
+            timeout,
+        }
+    }
+}
+
+impl<F, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<http::Response<BoxBody>, E>>,
+{
+    type Output = Result<http::Response<BoxBody>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project() {
+            ResponseFutureProj::Limited {
+                inner,
+                sleep,
+                path,
+                timeout,
+            } => {
+                if let Poll::Ready(result) = inner.poll(cx) {
+                    return Poll::Ready(result);
+                }
+                if sleep.poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                error!("{} timed out after {:?}", path, timeout);
+                // 返回之后 handler 的 future 跟着一起 drop 掉
+                let status = Status::deadline_exceeded(format!("{path} timed out"));
+                Poll::Ready(Ok(status.into_http()))
+            }
+            ResponseFutureProj::Unlimited { inner } => inner.poll(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deadline::get_deadline;
+    use std::convert::Infallible;
+    use tonic::body::empty_body;
+    use tonic::Code;
+    use tower::{Layer, ServiceExt};
+
+    /// handler 处理 delay 这么久, 返回 handler 里看到的剩余时间
+    async fn call(
+        timeout: &ServerTimeout,
+        path: &str,
+        grpc_timeout: Option<&str>,
+        delay: Duration,
+    ) -> (Option<Code>, Option<Duration>) {
+        let remaining = Arc::new(std::sync::Mutex::new(None));
+        let seen = remaining.clone();
+        let inner = tower::service_fn(move |_request: http::Request<BoxBody>| {
+            let seen = seen.clone();
+            async move {
+                // 截止时间在 future 里面才能拿到
+                *seen.lock().unwrap() = get_deadline()
+                    .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+                tokio::time::sleep(delay).await;
+                Ok::<_, Infallible>(http::Response::new(empty_body()))
+            }
+        });
+        let mut request = http::Request::builder().uri(path);
+        if let Some(grpc_timeout) = grpc_timeout {
+            request = request.header("grpc-timeout", grpc_timeout);
+        }
+        let response = timeout
+            .layer(inner)
+            .oneshot(request.body(empty_body()).unwrap())
+            .await
+            .unwrap();
+        let code = Status::from_header_map(response.headers()).map(|status| status.code());
+        let remaining = *remaining.lock().unwrap();
+        (code, remaining)
+    }
+
+    #[tokio::test]
+    async fn deadline_exceeded() {
+        let timeout = ServerTimeout::new(Some(Duration::from_millis(50)), HashMap::new());
+        let start = Instant::now();
+        let (code, _) = call(&timeout, "/user.User/Get", None, Duration::from_secs(1)).await;
+        assert_eq!(code, Some(Code::DeadlineExceeded));
+        assert!(start.elapsed() < Duration::from_millis(500));
+        // 没超时的正常返回
+        let (code, _) = call(&timeout, "/user.User/Get", None, Duration::ZERO).await;
+        assert_eq!(code, None);
+    }
+
+    #[tokio::test]
+    async fn min_of_grpc_timeout() {
+        let timeout = ServerTimeout::new(Some(Duration::from_secs(1)), HashMap::new());
+        // 调用方给的更短
+        let (code, remaining) = call(
+            &timeout,
+            "/user.User/Get",
+            Some("50m"),
+            Duration::from_secs(1),
+        )
+        .await;
+        assert_eq!(code, Some(Code::DeadlineExceeded));
+        assert!(remaining.unwrap() <= Duration::from_millis(50));
+        // 配置的更短
+        let (_, remaining) = call(&timeout, "/user.User/Get", Some("10S"), Duration::ZERO).await;
+        let remaining = remaining.unwrap();
+        assert!(remaining <= Duration::from_secs(1) && remaining > Duration::from_millis(900));
+    }
+
+    #[tokio::test]
+    async fn method_override() {
+        let methods = HashMap::from([("/user.User/Get".to_owned(), Duration::from_millis(50))]);
+        let timeout = ServerTimeout::new(Some(Duration::from_secs(1)), methods);
+        let (code, _) = call(&timeout, "/user.User/Get", None, Duration::from_millis(200)).await;
+        assert_eq!(code, Some(Code::DeadlineExceeded));
+        let (code, _) = call(&timeout, "/user.User/Add", None, Duration::from_millis(200)).await;
+        assert_eq!(code, None);
+        // 没有配置超时也没有 grpc-timeout 的不限制
+        let timeout = ServerTimeout::default();
+        let (code, remaining) = call(&timeout, "/user.User/Get", None, Duration::ZERO).await;
+        assert_eq!((code, remaining), (None, None));
+    }
+
+    #[test]
+    fn ignore_zero() {
+        let conf: ServerConf = serde_yaml::from_str(
+            r#"
+ServerName: user.rpc
+Model: Dev
+Endpoint: 127.0.0.1:50051
+Etcd:
+  Hosts: 127.0.0.1:2379
+Timeout: 0
+MethodTimeouts:
+  /user.User/Get: 0
+  /user.User/Add: 100
+"#,
+        )
+        .unwrap();
+        let timeout = ServerTimeout::from(&conf);
+        assert_eq!(timeout.get_timeout("/user.User/Get"), None);
+        assert_eq!(
+            timeout.get_timeout("/user.User/Add"),
+            Some(Duration::from_millis(100))
+        );
+    }
+}
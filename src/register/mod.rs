@@ -3,6 +3,16 @@ use crate::error::ZrpcError;
 
 #[tonic::async_trait]
 pub trait Register {
-    async fn register(mut self, server_instance: &ServiceInstance) -> Result<(), ZrpcError>;
-    // fn deregister();
+    type Handle: Deregister + Send + 'static;
+
+    /// 注册成功后返回一个句柄, 退出前通过句柄注销
+    async fn register(
+        mut self,
+        server_instance: &ServiceInstance,
+    ) -> Result<Self::Handle, ZrpcError>;
+}
+
+#[tonic::async_trait]
+pub trait Deregister {
+    async fn deregister(self) -> Result<(), ZrpcError>;
 }
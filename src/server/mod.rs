@@ -1,17 +1,26 @@
 use crate::common::ServiceInstance;
-use crate::register::Register;
+use crate::error::ZrpcError;
+use crate::register::{Deregister, Register};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
 use tonic::body::BoxBody;
 use tonic::codegen::http::{Request, Response};
 use tonic::codegen::Service;
 use tonic::service::Routes;
-use tonic::transport::server::Router;
-use tool::log::trace_log::info;
+use tonic::transport::server::{Router, TcpIncoming};
+use tool::log::trace_log::{error, info};
 use tower::layer::util::Identity;
 use tower::Layer;
 
+/// 收到退出信号后最多等注销这么久
+const DEREGISTER_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct Server<R> {
     register: R,
     server_instance: ServiceInstance,
+    tcp_nodelay: bool,
+    tcp_keepalive: Option<Duration>,
 }
 
 impl<R> Server<R>
@@ -22,10 +31,24 @@ where
         Self {
             register,
             server_instance,
+            tcp_nodelay: true,
+            tcp_keepalive: None,
         }
     }
 
-    pub async fn serve<L, F>(self, f: F)
+    /// 接收的连接是否设置 TCP_NODELAY, 默认开启, 和 tonic 一样
+    pub fn tcp_nodelay(mut self, enabled: bool) -> Self {
+        self.tcp_nodelay = enabled;
+        self
+    }
+
+    /// 接收的连接空闲多久后发送 TCP keepalive 探测, 默认不开启
+    pub fn tcp_keepalive(mut self, tcp_keepalive: Option<Duration>) -> Self {
+        self.tcp_keepalive = tcp_keepalive;
+        self
+    }
+
+    pub async fn serve<L, F>(self, f: F) -> Result<(), ZrpcError>
     where
         F: Fn(tonic::transport::Server<Identity>) -> Router<L> + Send + 'static,
         L: Layer<Routes>,
@@ -38,18 +61,35 @@ where
         let Self {
             register,
             server_instance,
+            tcp_nodelay,
+            tcp_keepalive,
         } = self;
-        let addr = server_instance.endpoint.parse().unwrap();
+        let addr: SocketAddr = server_instance.endpoint.parse()?;
 
-        let router = f(tonic::transport::Server::builder());
-        tokio::spawn(async move {
-            register.register(&server_instance).await.unwrap();
-        });
+        // 连接的参数要在这里设置, 自己传 incoming 的时候 tonic 不会用 builder 上的
+        let builder = tonic::transport::Server::builder()
+            .tcp_nodelay(tcp_nodelay)
+            .tcp_keepalive(tcp_keepalive);
+        let router = f(builder);
+        // 先监听端口再注册, 避免客户端拿到地址后连不上
+        let listener = TcpListener::bind(addr).await?;
+        let incoming = TcpIncoming::from_listener(listener, tcp_nodelay, tcp_keepalive)
+            .map_err(|err| anyhow::anyhow!(err))?;
         info!("Server listening on: {}", addr);
+        let register_handle = register.register(&server_instance).await?;
         router
-            .serve_with_shutdown(addr, Self::wait_for_quit())
-            .await
-            .unwrap();
+            .serve_with_incoming_shutdown(incoming, async move {
+                Self::wait_for_quit().await;
+                // 先从注册中心注销, 再停止接收新的连接, 避免客户端继续往这里发请求
+                match tokio::time::timeout(DEREGISTER_TIMEOUT, register_handle.deregister()).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => error!("deregister failed: {}", err),
+                    // 注册中心连不上的时候不能一直等, 实例会在租约过期后自己消失
+                    Err(_) => error!("deregister timeout after {:?}", DEREGISTER_TIMEOUT),
+                }
+            })
+            .await?;
+        Ok(())
     }
 
     async fn wait_for_quit() {